@@ -0,0 +1,18 @@
+use std::process::Command;
+
+fn main() {
+    // Embeds the commit being built for '--version', builds outside a git checkout simply omit it
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=PHOENIX_GIT_COMMIT={}", commit.trim());
+    }
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,132 @@
+// Evaluates the expression inside '$(( ))' using 64-bit integers which wrap on overflow, like other shells
+// Supports '+', '-', '*', '/', '%', parentheses, numbers and variable names, where an unset or empty variable is 0
+// 'lookup' finds the value of a variable, which must itself be a number
+pub fn evaluate(expr: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<i64, String> {
+    // An empty expression is 0, as in '$(( ))'
+    if expr.trim().is_empty() {
+        return Ok(0)
+    }
+
+    let mut parser = Parser { text: expr, index: 0, lookup };
+    let value = parser.sum()?;
+
+    match parser.peek() {
+        None => Ok(value),
+        Some(c) => Err(format!("Unexpected '{}'", c))
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    index: usize, // Byte offset of the next character to read
+    lookup: &'a dyn Fn(&str) -> Option<String>
+}
+
+impl<'a> Parser<'a> {
+    // The next character that isn't whitespace, which is left to be read
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.text[self.index..];
+        self.index += rest.len() - rest.trim_start().len();
+        self.text[self.index..].chars().next()
+    }
+
+    // Terms joined by '+' and '-', which bind more loosely than the other operators
+    fn sum(&mut self) -> Result<i64, String> {
+        let mut value = self.product()?;
+
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.index += 1;
+                    value = value.wrapping_add(self.product()?);
+                }
+
+                Some('-') => {
+                    self.index += 1;
+                    value = value.wrapping_sub(self.product()?);
+                }
+
+                _ => return Ok(value)
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<i64, String> {
+        let mut value = self.unary()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(op @ ('*' | '/' | '%')) => op,
+                _ => return Ok(value)
+            };
+
+            self.index += 1;
+            let rhs = self.unary()?;
+
+            value = match op {
+                '*' => value.wrapping_mul(rhs),
+                _ if rhs == 0 => return Err(String::from("Division by zero")),
+                '/' => value.wrapping_div(rhs),
+                _ => value.wrapping_rem(rhs)
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.peek() {
+            Some('-') => {
+                self.index += 1;
+                Ok(self.unary()?.wrapping_neg())
+            }
+
+            Some('+') => {
+                self.index += 1;
+                self.unary()
+            }
+
+            _ => self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        match self.peek() {
+            Some('(') => {
+                self.index += 1;
+                let value = self.sum()?;
+
+                if self.peek() != Some(')') {
+                    return Err(String::from("Missing ')'"))
+                }
+
+                self.index += 1;
+                Ok(value)
+            }
+
+            Some(c) if c.is_ascii_digit() => {
+                let digits = self.take_while(|c| c.is_ascii_alphanumeric());
+                digits.parse::<i64>().map_err(|_| format!("'{}' is not a valid number", digits))
+            }
+
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                let value = (self.lookup)(name).unwrap_or_default();
+
+                match value.trim() {
+                    "" => Ok(0),
+                    number => number.parse::<i64>().map_err(|_| format!("'{}' holds '{}', which is not a number", name, value))
+                }
+            }
+
+            Some(c) => Err(format!("Unexpected '{}'", c)),
+            None => Err(String::from("Expected a number or variable"))
+        }
+    }
+
+    fn take_while(&mut self, cond: impl Fn(char) -> bool) -> &'a str {
+        let start = self.index;
+        let len = self.text[start..].find(|c| !cond(c)).unwrap_or(self.text.len() - start);
+
+        self.index += len;
+        &self.text[start .. self.index]
+    }
+}
@@ -1,5 +1,14 @@
 use std::ops::Range;
 
+// Resolves a range against the source it was parsed from
+// Ranges are byte offsets on character boundaries, a range which isn't is reported rather than panicking
+pub fn span_text<'a>(source: &'a str, range: &Range<usize>) -> &'a str {
+    source.get(range.clone()).unwrap_or_else(|| {
+        eprintln!("Phoenix: internal error: {:?} is not a valid range of the input", range);
+        ""
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct Spanned<T: Clone> {
     pub value: T,
@@ -14,42 +23,121 @@ impl<T: Clone> Spanned<T> {
             span
         }
     }
+
+    // Resolves the span against the source it was parsed from
+    #[allow(dead_code)] // Mostly useful when inspecting an AST while debugging
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        span_text(source, &self.span)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Module {
-    pub stmts: Vec<Spanned<Program>>
+    pub stmts: Vec<Spanned<Stmt>>
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Command(Program),
+    Group(Group),
+    Assign(Assign)
+}
+
+impl Stmt {
+    // How the statement following this one is run
+    pub fn connector(&self) -> Connector {
+        match self {
+            Stmt::Command(program) => program.connector,
+            Stmt::Group(group) => group.connector,
+            Stmt::Assign(assign) => assign.connector
+        }
+    }
+}
+
+// Assignments written without a command, such as 'FOO=bar', which set the shell's own variables
+#[derive(Debug, Clone)]
+pub struct Assign {
+    pub assignments: Vec<Assignment>,
+    pub connector: Connector
+}
+
+// 'NAME=value', the name includes the '+' of 'NAME+=value'
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub name: Range<usize>,
+    pub value: Range<usize>
+}
+
+// Statements grouped with '( ... )', which run as a unit
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub body: Module,
+    pub subshell: bool, // Changes to the working directory and variables are undone once the group finishes
+    pub connector: Connector
 }
 
 #[derive(Debug, Clone)]
 pub struct Program {
     pub program: Range<usize>,
     pub argv: Vec<Range<usize>>,
-    pub stdin: StreamStrategy,
-    pub stdout: StreamStrategy
-    // We don't handle stderr in any special way
+    pub env: Vec<Assignment>, // Leading 'NAME=value' assignments for this invocation only
+    pub redirects: Vec<Redirect>, // Applied left to right, after stdout is connected to a pipe
+    pub piped: bool, // '|' - stdout goes to stdin of the next program
+    pub connector: Connector // How the statement following this one is run
 }
 
 impl Program {
     pub fn new(
         program: Range<usize>,
         argv: Vec<Range<usize>>,
-        stdin: StreamStrategy,
-        stdout: StreamStrategy
+        env: Vec<Assignment>,
+        redirects: Vec<Redirect>,
+        piped: bool,
+        connector: Connector
     ) -> Self {
         Self {
             program,
             argv,
-            stdin,
-            stdout
+            env,
+            redirects,
+            piped,
+            connector
         }
     }
+
+    pub fn program_str<'a>(&self, source: &'a str) -> &'a str {
+        span_text(source, &self.program)
+    }
+
+    pub fn argv_strs<'a>(&self, source: &'a str) -> Vec<&'a str> {
+        self.argv.iter().map(|arg| span_text(source, arg)).collect()
+    }
 }
 
+// Points one of a program's file descriptors somewhere else, such as '2> errors.log'
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub fd: usize, // 0 for input redirects, 1 or 2 for output
+    pub strategy: StreamStrategy,
+    pub span: Range<usize> // The operator and its target
+}
+
+// Where a redirect points its descriptor, which also decides whether it's read or written
 #[derive(Debug, Clone, PartialEq)]
 pub enum StreamStrategy {
-    Inherit, // Inherit from Phoenix
-    PipeFromFile(Range<usize>), // Pipe file content to stdin
-    PipeToFile(Range<usize>), // Pipe stdout to file
-    PipeToStdin // Pipe stdout to stdin of next program
-}
\ No newline at end of file
+    PipeFromFile(Range<usize>), // '<' - reads the file
+    ReadWriteFile(Range<usize>), // '<>' - reads and writes the file, creating it if needed
+    PipeToFile(Range<usize>, bool), // '>' - writes the file, the flag forces truncation even under noclobber
+    AppendToFile(Range<usize>), // '>>' - writes to the end of the file
+    HereString(Range<usize>), // '<<<' - the expanded word followed by a newline is piped in
+    HereDoc(Range<usize>, bool), // '<<' - the body is piped in, the flag is set when variables are expanded
+    Discard, // Writes to the platform null device
+    Duplicate(usize) // '>&' - shares the handle the other descriptor has at this point, as in '2>&1'
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    Sequence, // Always run the next statement
+    And, // '&&' - run the next statement only if this one succeeded
+    Or, // '||' - run the next statement only if this one failed
+    Background // '&' - don't wait for this statement before running the next
+}
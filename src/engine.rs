@@ -1,31 +1,138 @@
-use ariadne::{Label, Report, ReportKind, Source};
-use crate::ast::{Module, Program, Spanned, StreamStrategy};
-use std::{env, fs::File, io::{Error, ErrorKind}, path::PathBuf, process::{Command, Stdio}};
-use std::collections::HashMap;
+use ariadne::{Config, IndexType, Label, Report, ReportKind, Source};
+use crate::ast::{span_text, Assign, Assignment, Connector, Group, Module, Program, Spanned, Stmt, StreamStrategy};
+use crate::expand::{self, DEFAULT_IFS};
+use crate::input_lexer::{InputLexer, TokenType};
+use crate::input_parser::InputParser;
+use crate::line_editor::Completion;
+use crate::log::LogFlags;
+use std::{env, fs::File, io::{PipeReader, PipeWriter}, ops::Range, path::{Component, Path, PathBuf}, process::{Child, Command, ExitStatus, Stdio}};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 
 #[cfg(target_os = "windows")]
+#[allow(non_snake_case)]
 mod PLATFORM_VARS {
     pub const PATH_SEPARATOR: char = ';';
-    pub const BASE_DIR: &'static str = "USERPROFILE";
+    pub const BASE_DIR: &str = "USERPROFILE";
 }
 
 #[cfg(target_os = "linux")]
+#[allow(non_snake_case)]
 mod PLATFORM_VARS {
-    const PATH_SEPARATOR: char = ':';
-    const BASE_DIR: &'static str = "HOME";
+    pub const PATH_SEPARATOR: char = ':';
+    pub const BASE_DIR: &str = "HOME";
+}
+
+// Status of a command stopped by a closed pipe, matching a process killed by SIGPIPE
+const BROKEN_PIPE_STATUS: i32 = 128 + 13;
+
+// Variables worked out each time they're read rather than stored
+const DYNAMIC_VARS: [&str; 3] = ["RANDOM", "SECONDS", "PWD"];
+
+// Pipelines longer than this are refused unless the limit is raised, rather than running out of file descriptors
+const DEFAULT_MAX_PIPELINE: usize = 256;
+
+// Behaviours toggled with the 'set' builtin
+#[derive(Debug, Clone)]
+pub struct ShellOptions {
+    pub errexit: bool, // 'set -e' - exit as soon as a pipeline fails
+    pub xtrace: bool, // 'set -x' - print each command before running it
+    pub pipefail: bool, // 'set -o pipefail' - a pipeline fails if any of its commands fail, not just the last
+    pub noclobber: bool, // 'set -C' - '>' refuses to truncate existing files
+    pub prompttilde: bool, // 'set -o prompttilde' - the prompt shows the home directory as '~'
+    pub unbuffered: bool, // 'set -o unbuffered' - builtins write their output as soon as it's produced
+    pub max_pipeline: usize // 'set -o maxpipeline=N' - the most commands a single pipeline may have
+}
+
+impl Default for ShellOptions {
+    fn default() -> Self {
+        Self {
+            errexit: false,
+            xtrace: false,
+            pipefail: false,
+            noclobber: false,
+            prompttilde: true,
+            unbuffered: false,
+            max_pipeline: DEFAULT_MAX_PIPELINE
+        }
+    }
+}
+
+impl ShellOptions {
+    // Resolves an option by the name used with 'set -o'
+    pub fn by_name(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "errexit" => Some(&mut self.errexit),
+            "xtrace" => Some(&mut self.xtrace),
+            "pipefail" => Some(&mut self.pipefail),
+            "noclobber" => Some(&mut self.noclobber),
+            "prompttilde" => Some(&mut self.prompttilde),
+            "unbuffered" => Some(&mut self.unbuffered),
+            _ => None
+        }
+    }
+
+    // Resolves an option by its single letter flag
+    pub fn by_flag(&mut self, flag: char) -> Option<&mut bool> {
+        match flag {
+            'e' => Some(&mut self.errexit),
+            'x' => Some(&mut self.xtrace),
+            'C' => Some(&mut self.noclobber),
+            _ => None
+        }
+    }
+
+    pub fn list(&self) -> [(&'static str, bool); 6] {
+        [
+            ("errexit", self.errexit),
+            ("xtrace", self.xtrace),
+            ("pipefail", self.pipefail),
+            ("noclobber", self.noclobber),
+            ("prompttilde", self.prompttilde),
+            ("unbuffered", self.unbuffered)
+        ]
+    }
+}
+
+// A pipeline started in the background with '&'
+pub struct Job {
+    pub id: usize,
+    pub children: Vec<Child>,
+    pub command: String // Text of the pipeline, shown when the job finishes
 }
 
 pub struct Engine {
-    pub cur_dir: String, // TODO: Implement paths
+    pub cur_dir: String, // Logical working directory, kept in step with the process by 'sync_cwd'
+    pub last_status: i32, // Exit status of the most recent pipeline
+    pub options: ShellOptions,
+    jobs: Vec<Job>, // Pipelines running in the background, in the order they were started
+    disowned: Vec<Child>, // Processes of jobs removed with 'disown', reaped quietly once they exit
+    dir_stack: Vec<String>, // Directories saved by 'pushd', the most recent last
     path: Vec<String>,
-    vars: Vec<String>, // TODO: Implement environment variables. Load from Windows / bashrc ?
-    builtins: HashMap<&'static str, builtins::BuiltinFn>,
-    source: String
+    vars: HashMap<String, String>, // Shell variables, seeded from the environment Phoenix was started with
+    exported: HashSet<String>, // Names of variables passed on to child processes
+    integers: HashSet<String>, // Names of variables set with 'declare -i', whose values are evaluated as arithmetic
+    builtins: HashMap<&'static str, builtins::Builtin>,
+    source: Rc<str>, // Input being executed, shared with builtins for resolving spans
+    source_name: String, // Where the source came from, used as the id in error reports
+    args: Vec<String>, // Expanded arguments of the builtin being run
+    positional: Vec<String>, // '$0' followed by the arguments of the running script, empty outside a script
+    history: Vec<String>, // Lines entered at the prompt, oldest first
+    aliases: HashMap<String, String>, // Words replaced with their value when they start a command
+    abbreviations: HashMap<String, String>, // Words replaced in the line editor as they're typed, set with 'abbr'
+    completions: HashMap<String, Vec<String>>, // Words completed for the arguments of a command, set with 'complete'
+    expansion_error: Cell<Option<String>>, // The first failed arithmetic expansion of the statement, reported before it runs
+    started: std::time::Instant, // When the shell started, for '$SECONDS'
+    random_state: Cell<u64> // State of the generator behind '$RANDOM', never zero
 }
 
 impl Engine {
     pub fn new() -> Self {
+        let mut vars: HashMap<String, String> = env::vars().collect();
+        vars.entry(String::from("IFS")).or_insert_with(|| String::from(DEFAULT_IFS));
+
         let path = env::var_os("PATH")
             .unwrap()
             .to_str()
@@ -34,242 +141,3568 @@ impl Engine {
             .map(|p| p.to_string())
             .collect();
 
-        Self {
-            cur_dir: Engine::get_base_dir(),
+        // PWD is only trusted while it names the real working directory, which keeps a symlinked path intact
+        let mut engine = Self {
+            cur_dir: env::var("PWD").unwrap_or_default(),
+            last_status: 0,
+            options: ShellOptions::default(),
+            jobs: Vec::new(),
+            disowned: Vec::new(),
+            dir_stack: Vec::new(),
             path,
-            vars: Vec::new(),
+            vars,
+            exported: env::vars().map(|(name, _)| name).collect(),
+            integers: HashSet::new(),
             builtins: builtins::builtin_registry(),
-            source: String::new()
+            source: Rc::from(""),
+            source_name: String::from("stdin"),
+            args: Vec::new(),
+            positional: Vec::new(),
+            history: Vec::new(),
+            aliases: HashMap::new(),
+            abbreviations: HashMap::new(),
+            completions: HashMap::new(),
+            expansion_error: Cell::new(None),
+            started: std::time::Instant::now(),
+            random_state: Cell::new(random_seed())
+        };
+
+        engine.sync_cwd();
+        engine
+    }
+
+    // Lexes, parses and executes a single line of input, returning the status it finished with
+    pub fn run(&mut self, name: &str, line: &str) -> std::io::Result<i32> {
+        let expanded = self.expand_aliases(line);
+        let line = expanded.as_str();
+
+        let tokens = InputLexer::new(name, line)
+            .filter(|token| token.typ != TokenType::Whitespace)
+            .collect();
+
+        let module = InputParser::new(name, line, tokens).build_ast();
+
+        // The source is shared with builtins once per input rather than copied for every command
+        // Nested runs such as 'source' restore the caller's source afterwards so its spans stay valid
+        let outer_source = std::mem::replace(&mut self.source, Rc::from(line));
+        let outer_name = std::mem::replace(&mut self.source_name, name.to_string());
+
+        let result = self.execute(line, module);
+
+        self.source = outer_source;
+        self.source_name = outer_name;
+
+        result
+    }
+
+    // Replaces an alias starting each command with its value, before the line is lexed to run
+    fn expand_aliases(&self, line: &str) -> String {
+        if self.aliases.is_empty() {
+            return line.to_string()
+        }
+
+        let mut starts = Vec::new();
+        let mut command_start = true;
+
+        for token in InputLexer::new("", line).quiet().filter(|token| token.typ != TokenType::Whitespace) {
+            let text = &line[token.start .. token.end];
+
+            if command_start && token.typ == TokenType::Identifier {
+                starts.push(token.start .. token.end);
+            }
+
+            // Matches where the lexer expects a statement to start, including after '{'
+            command_start = match token.typ {
+                TokenType::Identifier => command_start && text == "{",
+                TokenType::Assignment => command_start,
+                TokenType::Newline | TokenType::Semicolon | TokenType::LParen | TokenType::Pipe
+                    | TokenType::And | TokenType::LogicalAnd | TokenType::LogicalOr => true,
+                _ => false
+            };
+        }
+
+        let mut result = line.to_string();
+
+        // Replacing from the end keeps the offsets of earlier words valid
+        for range in starts.into_iter().rev() {
+            if let Some(value) = self.expand_alias(&line[range.clone()], &mut HashSet::new()) {
+                result.replace_range(range, &value);
+            }
+        }
+
+        result
+    }
+
+    // The first word of a value may be another alias, but a name isn't expanded twice for the same command
+    // so 'alias ls="ls -l"' expands once and aliases naming each other stop rather than looping
+    fn expand_alias(&self, word: &str, expanded: &mut HashSet<String>) -> Option<String> {
+        let value = self.aliases.get(word)?;
+
+        if !expanded.insert(word.to_string()) {
+            return None
+        }
+
+        let end = value.find(char::is_whitespace).unwrap_or(value.len());
+        let (first, rest) = value.split_at(end);
+
+        match self.expand_alias(first, expanded) {
+            Some(first) => Some(first + rest),
+            None => Some(value.clone())
+        }
+    }
+
+    // Runs each line of a file in the current shell, as used by 'source'
+    pub fn run_file(&mut self, path: &str) -> std::io::Result<()> {
+        let contents = std::fs::read_to_string(path)?;
+
+        let mut pending = String::new();
+
+        // Lines are run one at a time unless a statement continues onto the next, like a here-document
+        for line in contents.lines() {
+            // Blank lines and comments are skipped between statements, but belong to a here-document being read
+            if pending.is_empty() && Engine::is_blank_or_comment(line) {
+                continue;
+            }
+
+            pending.push_str(line);
+            pending.push('\n');
+
+            if !InputLexer::is_incomplete(&pending) {
+                self.run(path, &pending)?;
+                pending.clear();
+            }
         }
+
+        // Running what's left reports the unterminated statement
+        if !pending.is_empty() {
+            self.run(path, &pending)?;
+        }
+
+        Ok(())
+    }
+
+    // Whether a line of a file has nothing to run, such as a '#' comment or the '#!' line naming the interpreter
+    fn is_blank_or_comment(line: &str) -> bool {
+        let line = line.trim_start();
+        line.is_empty() || line.starts_with('#')
+    }
+
+    // Runs a command string given with '-c', where 'args' are '$0' followed by the positional parameters
+    pub fn run_command(&mut self, command: &str, args: Vec<String>) -> std::io::Result<i32> {
+        self.positional = args;
+        self.run("-c", command)
     }
 
-    pub fn execute(&mut self, source: &str, module: Module) {
-        self.source = source.to_string(); // Save the source to the instance for builtins to reference
-        let mut iter = module.stmts.into_iter().peekable();
+    // Runs a file with its own positional parameters, restoring the caller's once it finishes
+    pub fn run_script(&mut self, path: &str, args: Vec<String>) -> std::io::Result<()> {
+        let mut positional = vec![path.to_string()];
+        positional.extend(args);
+
+        let outer = std::mem::replace(&mut self.positional, positional);
+        let result = self.run_file(path);
+        self.positional = outer;
+
+        result
+    }
+
+    // Runs each statement in turn and returns the final status, stopping early on an I/O error the shell can't recover from
+    pub fn execute(&mut self, source: &str, module: Module) -> std::io::Result<i32> {
+        let mut iter = module.stmts.into_iter();
+        let mut skip = false;
 
         while let Some(stmt) = iter.next() {
-            let mut pipe_chain = vec![stmt];
+            let connector = stmt.value.connector();
+            let mut pipe_chain = Vec::new();
+
+            let (group, assign) = match stmt.value {
+                Stmt::Group(group) => (Some(group), None),
+                Stmt::Assign(assign) => (None, Some(Spanned::new(assign, stmt.span))),
+                Stmt::Command(program) => {
+                    pipe_chain.push(Spanned::new(program, stmt.span));
+                    (None, None)
+                }
+            };
+
+            // The parser only allows commands on either side of a pipe
+            while pipe_chain.last().is_some_and(|stmt| stmt.value.piped) {
+                let Some(Spanned { value: Stmt::Command(program), span }) = iter.next() else {
+                    break
+                };
 
-            while let Some(_) = iter.peek() {
-                if pipe_chain.last().unwrap().value.stdout == StreamStrategy::PipeToStdin {
-                    pipe_chain.push(iter.next().unwrap());
+                pipe_chain.push(Spanned::new(program, span));
+            }
+
+            let connector = pipe_chain.last().map_or(connector, |stmt| stmt.value.connector);
+
+            // A skipped pipeline leaves the status untouched so 'a && b || c' runs 'c' when 'a' fails
+            if !skip {
+                // Builtins, groups and assignments run inside the shell itself, so they can't be sent to the background
+                let is_builtin = pipe_chain.len() == 1
+                    && self.builtins.contains_key(pipe_chain[0].value.program_str(source));
+
+                let result = if pipe_chain.len() > self.options.max_pipeline {
+                    self.report_long_pipeline(&pipe_chain);
+                    Ok(())
+                } else if let Some(group) = group {
+                    self.execute_group(source, group)
+                } else if let Some(assign) = assign {
+                    self.execute_assign(source, assign);
+                    Ok(())
+                } else if connector == Connector::Background && !is_builtin {
+                    self.execute_background(source, pipe_chain)
+                } else if pipe_chain.len() == 1 {
+                    // Single command, no piping
+                    self.execute_single(source, pipe_chain.pop().unwrap())
                 } else {
-                    break;
+                    // We have a pipe chain so execute each statement individually and pipe stdio accordingly
+                    self.execute_pipeline(source, pipe_chain)
+                };
+
+                self.sync_cwd();
+
+                // A reader which exits early, like 'head', closes the pipe and that only ends the command writing to it
+                match result {
+                    Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => self.last_status = BROKEN_PIPE_STATUS,
+                    result => result?
                 }
             }
 
-            if pipe_chain.len() == 1 {
-                // Single command, no piping
-                self.execute_single(source, pipe_chain.pop().unwrap()).unwrap();
-            } else {
-                // We have a pipe chain so execute each statement individually and pipe stdio accordingly
-                self.execute_pipeline(source, pipe_chain).unwrap();
+            // Like other shells, only the last pipeline of a '&&' or '||' list can trigger errexit
+            if self.options.errexit && !skip && connector == Connector::Sequence && self.last_status != 0 {
+                std::process::exit(self.last_status);
             }
+
+            skip = match connector {
+                Connector::Sequence | Connector::Background => false,
+                Connector::And => self.last_status != 0,
+                Connector::Or => self.last_status == 0
+            };
+        }
+
+        Ok(self.last_status)
+    }
+
+    fn report_long_pipeline(&mut self, chain: &[Spanned<Program>]) {
+        let span = chain[0].span.start .. chain[chain.len() - 1].span.end;
+
+        Report::build(ReportKind::Error, (self.source_name.as_str(), 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Pipeline too long")
+            .with_label(
+                Label::new((self.source_name.as_str(), span))
+                    .with_message(format!("This pipeline has {} commands, more than the limit of {}", chain.len(), self.options.max_pipeline))
+            )
+            .with_note("The limit can be raised with 'set -o maxpipeline=N'")
+            .finish()
+            .eprint((self.source_name.as_str(), Source::from(&*self.source)))
+            .unwrap();
+
+        self.last_status = 2;
+    }
+
+    // Phoenix doesn't fork, so a subshell is emulated by restoring the state its statements may have changed
+    fn execute_group(&mut self, source: &str, group: Group) -> std::io::Result<()> {
+        if !group.subshell {
+            return self.execute(source, group.body).map(drop)
+        }
+
+        let cur_dir = self.cur_dir.clone();
+        let vars = self.vars.clone();
+        let exported = self.exported.clone();
+        let options = self.options.clone();
+        let dir_stack = self.dir_stack.clone();
+        let positional = self.positional.clone();
+
+        let result = self.execute(source, group.body);
+
+        // A directory removed in the meantime is dealt with by 'sync_cwd'
+        if env::set_current_dir(&cur_dir).is_ok() {
+            self.cur_dir = cur_dir;
+        }
+
+        self.vars = vars;
+        self.exported = exported;
+        self.options = options;
+        self.dir_stack = dir_stack;
+        self.positional = positional;
+
+        result.map(drop)
+    }
+
+    // Sets shell variables, where each value sees the variables assigned before it
+    // but a failed expansion leaves them all unchanged
+    fn execute_assign(&mut self, source: &str, assign: Spanned<Assign>) {
+        self.expansion_error.take();
+        self.trace_words(source, &assign.value.assignments, std::iter::empty());
+        self.last_status = 0;
+
+        let outer = self.vars.clone();
+
+        for assignment in &assign.value.assignments {
+            let (name, value) = self.assignment(span_text(source, &assignment.name), span_text(source, &assignment.value));
+            self.vars.insert(name.to_string(), value);
+        }
+
+        if self.report_expansion_error(source, assign.span) {
+            self.vars = outer;
         }
     }
 
     fn execute_pipeline(&mut self, source: &str, chain: Vec<Spanned<Program>>) -> std::io::Result<()> {
+        if let Some(children) = self.spawn_pipeline(source, chain)? {
+            self.last_status = self.wait_children(children)?;
+        }
+
+        Ok(())
+    }
+
+    fn execute_background(&mut self, source: &str, chain: Vec<Spanned<Program>>) -> std::io::Result<()> {
+        let command = span_text(source, &(chain[0].span.start .. chain[chain.len() - 1].span.end)).to_string();
+
+        if let Some(children) = self.spawn_pipeline(source, chain)? {
+            let id = self.jobs.last().map(|job| job.id + 1).unwrap_or(1);
+
+            // Like other shells the job is announced with the id of its last process
+            if let Some(child) = children.last() {
+                eprintln!("[{}] {}", id, child.id());
+            }
+
+            self.jobs.push(Job { id, children, command });
+            self.last_status = 0;
+        }
+
+        Ok(())
+    }
+
+    // Reports background jobs which have finished since the last prompt and drops them from the job table
+    pub fn notify_jobs(&mut self) {
+        self.disowned.retain_mut(|child| !matches!(child.try_wait(), Ok(Some(_))));
+
+        let mut index = 0;
+
+        while index < self.jobs.len() {
+            let statuses: Vec<_> = self.jobs[index].children.iter_mut()
+                .map(|child| child.try_wait().ok().flatten())
+                .collect();
+
+            // A pipeline is done once every process in it has exited, its status is that of the last one
+            if statuses.iter().any(Option::is_none) {
+                index += 1;
+                continue;
+            }
+
+            let job = self.jobs.remove(index);
+
+            match statuses.last().copied().flatten().map_or(0, exit_code) {
+                0 => eprintln!("[{}]+ Done {}", job.id, job.command),
+                code => eprintln!("[{}]+ Exit {} {}", job.id, code, job.command)
+            }
+        }
+    }
+
+    // Spawns every program in the chain without waiting, returning None if an error was reported instead
+    fn spawn_pipeline(&mut self, source: &str, chain: Vec<Spanned<Program>>) -> std::io::Result<Option<Vec<Child>>> {
         let mut children = Vec::new();
         let mut prev_stdout = None;
 
         for stmt in chain {
-            if self.builtins.contains_key(&source[stmt.value.program.clone()]) {
-                Report::build(ReportKind::Error, ("stdin", 0..0))
+            if self.builtins.contains_key(stmt.value.program_str(source)) {
+                Report::build(ReportKind::Error, (self.source_name.as_str(), 0..0))
+                    .with_config(Config::new().with_index_type(IndexType::Byte))
                     .with_message("Unsupported pipe operation")
                     .with_label(
-                        Label::new(("stdin", stmt.span))
+                        Label::new((self.source_name.as_str(), stmt.span))
                             .with_message("Unable to pipe stdio between internal commands")
                     )
                     .finish()
-                    .print(("stdin", Source::from(source)))
+                    .print((self.source_name.as_str(), Source::from(source)))
                     .unwrap();
 
-                return Ok(())
+                return Ok(None)
             }
 
-            let executable = self.find_executable(&source[stmt.value.program])?;
-            let mut cmd = Command::new(executable);
-            cmd.args(stmt.value.argv.iter().map(|arg| &source[arg.clone()]));
+            let Some(executable) = self.find_executable(stmt.value.program_str(source)) else {
+                self.report_unknown_command(source, stmt.value.program);
+                self.last_status = 127;
+                return Ok(None)
+            };
 
-            let stdin = match prev_stdout.take() {
-                Some(stdout) => Stdio::from(stdout),
-                None => match stmt.value.stdin {
-                    StreamStrategy::PipeFromFile(path) => {
-                        let file = File::open(&source[path])?;
-                        Stdio::from(file)
-                    }
+            self.expansion_error.take();
+            let argv = expand::expand_argv(self, source, &stmt.value);
 
-                    // First statement meaning we can guarantee it's inhering stdin if not from above file
-                    _ => Stdio::inherit()
-                }
-            };
+            if self.report_expansion_error(source, stmt.span.clone()) {
+                return Ok(None)
+            }
 
-            cmd.stdin(stdin);
+            self.trace(source, &stmt.value, &argv);
 
-            let stdout = match stmt.value.stdout {
-                StreamStrategy::PipeToStdin => Stdio::piped(),
-                StreamStrategy::PipeToFile(ref path) => {
-                    let file = File::create(&source[path.clone()])?;
-                    Stdio::from(file)
-                }
+            let mut cmd = self.new_command(source, executable, argv, &stmt.value);
 
-                // Default to inheriting if not piping to next statement or to a file
-                _ => Stdio::inherit()
+            // Each stage applies its own redirects over its pipes, so a '2>' or '2>&1' only changes that stage's stderr
+            let Some((handles, reader)) = self.open_streams(source, &stmt.value, prev_stdout.take())? else {
+                return Ok(None)
             };
 
-            cmd.stdout(stdout);
+            let input = attach_streams(&mut cmd, handles)?;
 
+            self.log_spawn(&cmd, &stmt.value);
             let mut child = cmd.spawn()?;
+            feed_stdin(&mut child, input);
 
-            if stmt.value.stdout == StreamStrategy::PipeToStdin {
-                prev_stdout = Some(child.stdout.take().unwrap());
-            }
-
+            prev_stdout = reader;
             children.push(child);
         }
 
+        Ok(Some(children))
+    }
+
+    // Waits for every child of a pipeline and computes the pipeline's status
+    fn wait_children(&self, children: Vec<Child>) -> std::io::Result<i32> {
+        let mut statuses = Vec::new();
+
         for mut child in children {
-            child.wait()?;
+            statuses.push(exit_code(child.wait()?));
         }
 
-        Ok(())
+        // Under pipefail the last command to fail decides the status, as in other shells
+        Ok(if self.options.pipefail {
+            statuses.iter().copied().rfind(|status| *status != 0).unwrap_or(0)
+        } else {
+            statuses.last().copied().unwrap_or(0)
+        })
     }
 
     fn execute_single(&mut self, source: &str, stmt: Spanned<Program>) -> std::io::Result<()> {
-        // Check if it is a built in command and execute before assuming it is an external command
-        if let Some(builtin) = self.builtins.get(&source[stmt.value.program.clone()]) {
-            return builtin(self, &stmt);
+        self.expansion_error.take();
+        let argv = expand::expand_argv(self, source, &stmt.value);
+
+        if self.report_expansion_error(source, stmt.span.clone()) {
+            return Ok(())
         }
 
-        let executable = self.find_executable(&source[stmt.value.program])?;
-        let mut cmd = Command::new(executable);
-        cmd.args(stmt.value.argv.iter().map(|arg| &source[arg.clone()]));
+        self.trace(source, &stmt.value, &argv);
+        self.dispatch(source, stmt, argv)
+    }
 
-        match stmt.value.stdin {
-            StreamStrategy::PipeFromFile(path) => {
-                let file = File::open(&source[path])?;
-                cmd.stdin(Stdio::from(file));
-            }
+    // Runs an already expanded statement as a builtin or external program
+    fn dispatch(&mut self, source: &str, stmt: Spanned<Program>, argv: Vec<String>) -> std::io::Result<()> {
+        // Check if it is a built in command and execute before assuming it is an external command
+        if let Some(builtin) = self.builtins.get(stmt.value.program_str(source)).copied() {
+            // Builtins succeed unless they set a status of their own
+            // Arguments are expanded beforehand so '$?' still refers to the previous command
+            self.args = argv;
+            self.last_status = 0;
+            let result = (builtin.run)(self, &stmt);
 
-            _ => { cmd.stdin(Stdio::inherit()); }
+            // Output without a trailing newline is written out before whatever runs next shares the terminal or pipe
+            let flushed = std::io::Write::flush(&mut std::io::stdout());
+            return result.and(flushed);
         }
 
-        match stmt.value.stdout {
-            StreamStrategy::PipeToFile(path) => {
-                let file = File::create(&source[path])?;
-                cmd.stdout(Stdio::from(file));
+        let Some(executable) = self.find_executable(stmt.value.program_str(source)) else {
+            if !self.run_not_found_handler(source, &stmt.value, &argv)? {
+                self.report_unknown_command(source, stmt.value.program);
+                self.last_status = 127;
             }
 
-            _ => { cmd.stdout(Stdio::inherit()); }
-        }
+            return Ok(())
+        };
+
+        let Some((mut cmd, input)) = self.prepare_command(source, executable, argv, &stmt.value)? else {
+            return Ok(())
+        };
 
-        // TODO: Implement program not found error
         let mut child = cmd.spawn()?;
-        child.wait()?;
+        feed_stdin(&mut child, input);
+        self.last_status = exit_code(child.wait()?);
 
         Ok(())
     }
 
-    fn find_executable(&self, cmd: &str) -> std::io::Result<PathBuf> {
-        let extensions = if cfg!(windows) {
-            vec!["exe", "cmd", "bat", "com"]
-        } else {
-            vec![""]
+    // The command for an external program with its redirects attached, along with any text to write to its stdin
+    // None means a redirect couldn't be opened and the failure has been reported
+    fn prepare_command(&mut self, source: &str, executable: PathBuf, argv: Vec<String>, program: &Program) -> std::io::Result<Option<(Command, Option<String>)>> {
+        let mut cmd = self.new_command(source, executable, argv, program);
+
+        let Some((handles, _)) = self.open_streams(source, program, None)? else {
+            return Ok(None)
         };
 
-        for dir in &self.path {
-            for ext in &extensions {
-                let mut full_path = PathBuf::from(dir).join(cmd);
+        let input = attach_streams(&mut cmd, handles)?;
+        self.log_spawn(&cmd, program);
 
-                if !ext.is_empty() {
-                    full_path.set_extension(ext);
-                }
+        Ok(Some((cmd, input)))
+    }
 
-                if full_path.exists() && full_path.is_file() {
-                    return Ok(full_path)
-                }
-            }
+    // Runs the program named by 'command_not_found_handler' in place of a command that couldn't be found
+    // The handler may include arguments of its own and is given the missing name followed by its arguments,
+    // its status becomes the status of the command. Returns false when no handler ran.
+    // Only programs on the PATH can be handlers, so a missing handler falls back to the usual error instead of recursing
+    fn run_not_found_handler(&mut self, source: &str, program: &Program, argv: &[String]) -> std::io::Result<bool> {
+        let Some(handler) = self.lookup_var("command_not_found_handler") else {
+            return Ok(false)
+        };
+
+        let mut words = handler.split_whitespace().map(String::from);
+        let Some(executable) = words.next().and_then(|name| self.find_executable(&name)) else {
+            return Ok(false)
+        };
+
+        let args = words
+            .chain(std::iter::once(program.program_str(source).to_string()))
+            .chain(argv.iter().cloned())
+            .collect();
+
+        let mut cmd = self.new_command(source, executable, args, program);
+        self.log_spawn(&cmd, program);
+        self.last_status = exit_code(cmd.status()?);
+
+        Ok(true)
+    }
+
+    // Reports an expansion which failed while the statement was expanded, in which case it shouldn't run
+    fn report_expansion_error(&mut self, source: &str, span: Range<usize>) -> bool {
+        let Some(err) = self.expansion_error.take() else {
+            return false
+        };
+
+        Report::build(ReportKind::Error, (self.source_name.as_str(), 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Invalid arithmetic")
+            .with_label(
+                Label::new((self.source_name.as_str(), span))
+                    .with_message(err)
+            )
+            .finish()
+            .print((self.source_name.as_str(), Source::from(source)))
+            .unwrap();
+
+        self.last_status = 1;
+        true
+    }
+
+    pub fn lookup_var(&self, name: &str) -> Option<String> {
+        if let Ok(index) = name.parse::<usize>() {
+            return self.positional.get(index).cloned()
         }
 
-        // TODO: Can we generate a ariadne error somehow?
-        Err(Error::new(ErrorKind::NotFound, format!("Unrecognized command '{}'", cmd)))
+        match name {
+            "RANDOM" => Some(self.random().to_string()),
+            "SECONDS" => Some(self.started.elapsed().as_secs().to_string()),
+            "PWD" => Some(self.cur_dir.clone()),
+            _ => self.vars.get(name).cloned()
+        }
     }
 
-    fn get_base_dir() -> String {
-        env::var_os(PLATFORM_VARS::BASE_DIR).unwrap().into_string().unwrap()
+    // Next value of '$RANDOM' between 0 and 32767, from a xorshift generator
+    fn random(&self) -> u16 {
+        let mut state = self.random_state.get();
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        self.random_state.set(state);
+
+        (state >> 49) as u16
     }
-}
 
-// TODO: Finish implementing builtins module
-mod builtins {
-    use std::{collections::HashMap, env, io::{Read, Write}};
-    use crate::{ast::{Program, Spanned}, engine::Engine};
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
 
-    pub type BuiltinFn = fn(&mut crate::Engine, &Spanned<Program>) -> std::io::Result<()>;
+    // Records why an expansion failed, only the first is kept since it's usually the cause of any which follow
+    pub fn expansion_failed(&self, err: String) {
+        let first = self.expansion_error.take().unwrap_or(err);
+        self.expansion_error.set(Some(first));
+    }
 
-    pub fn builtin_registry() -> HashMap<&'static str, BuiltinFn> {
-        HashMap::from([
-            ("cd", cd as BuiltinFn),
-            ("ls", ls as BuiltinFn),
-            ("echo", echo as BuiltinFn),
-            ("clear", clear as BuiltinFn),
-            ("exit", exit as BuiltinFn)
-        ])
+    // Resolves an assignment to the variable's name and new value, 'NAME+=value' appends to the current value
+    fn assignment<'a>(&self, name: &'a str, value: &str) -> (&'a str, String) {
+        let value = expand::expand_word(self, value);
+
+        let (name, append) = match name.strip_suffix('+') {
+            Some(name) => (name, true),
+            None => (name, false)
+        };
+
+        // Integer variables hold the result of the expression assigned, and '+=' adds to them
+        if self.integers.contains(name) {
+            let current = if append { self.lookup_var(name).unwrap_or_default() } else { String::new() };
+            let sum = expand::arithmetic(self, &value).zip(expand::arithmetic(self, &current)).map(|(value, current)| current.wrapping_add(value));
+            return (name, sum.unwrap_or_default().to_string())
+        }
+
+        match append {
+            true => (name, self.lookup_var(name).unwrap_or_default() + &value),
+            false => (name, value)
+        }
     }
 
-    fn cd(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
-        // TODO: Implement 'cd' command with no argv that should go back to home directory
-        // TODO: Implement implicit relative paths such as 'C:\>cd Users' currently moves to 'Users\>' which doesn't exist
-        // TODO: Lexer crashes with no token implementation of 'cd ..\'
-        if let Some(path) = stmt.value.argv.get(0) {
-            let path = str::from_utf8(&engine.source.as_bytes()[path.clone()]).unwrap();
+    // Builds the command for an external program, whose environment is exactly the exported variables
+    // plus any assignments written before the program name
+    fn new_command(&self, source: &str, executable: PathBuf, argv: Vec<String>, program: &Program) -> Command {
+        let mut cmd = Command::new(executable);
+        cmd.args(argv);
+        cmd.env_clear();
+        cmd.envs(self.exported.iter().filter_map(|name| Some((name, self.lookup_var(name)?))));
+        cmd.envs(program.env.iter().map(|assignment| self.assignment(span_text(source, &assignment.name), span_text(source, &assignment.value))));
 
-            env::set_current_dir(path)?;
-            engine.cur_dir = path.to_string();
-        } else {
-            let path = Engine::get_base_dir();
-            env::set_current_dir(&path)?;
-            engine.cur_dir = path;
+        cmd
+    }
+
+    // Traces a program about to be spawned when 'PHOENIX_LOG' includes 'exec'
+    fn log_spawn(&self, cmd: &Command, program: &Program) {
+        if LogFlags::get().exec {
+            eprintln!(
+                "[exec] {:?} {:?} piped={} redirects={:?}",
+                cmd.get_program(),
+                cmd.get_args().collect::<Vec<_>>(),
+                program.piped,
+                program.redirects
+            );
         }
+    }
 
-        Ok(())
+    // Prints a command to stderr before it runs when 'set -x' is enabled
+    fn trace(&self, source: &str, program: &Program, argv: &[String]) {
+        let command = std::iter::once(program.program_str(source).to_string()).chain(argv.iter().cloned());
+        self.trace_words(source, &program.env, command);
     }
 
-    fn ls(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
-        std::fs::read_dir(engine.cur_dir.as_str()).unwrap().for_each(|entry| {
-            println!("{}", entry.unwrap().file_name().display());
-        });
+    // Prints the assignments followed by the words of the command they apply to, if any
+    fn trace_words(&self, source: &str, env: &[Assignment], command: impl Iterator<Item = String>) {
+        if !self.options.xtrace {
+            return;
+        }
 
-        println!();
+        let words = env.iter()
+            .map(|assignment| format!("{}={}", span_text(source, &assignment.name), expand::expand_word(self, span_text(source, &assignment.value))))
+            .chain(command)
+            .map(|word| {
+                // Quote words that would otherwise be ambiguous when read back
+                if word.is_empty() || word.contains(char::is_whitespace) {
+                    format!("'{}'", word)
+                } else {
+                    word
+                }
+            })
+            .collect::<Vec<_>>();
 
-        Ok(())
+        eprintln!("+ {}", words.join(" "));
     }
 
-    fn echo(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
-        if stmt.value.argv.len() > 0 {
-            let content = &engine.source[stmt.value.argv[0].clone()];
-            println!("{}", content);
-        } else {
-            // TODO: We don't support piping for internals
-            // could we possibly change that to support piping *to* internals at least
-            let mut buffer = Vec::new();
-            std::io::stdin().read_to_end(&mut buffer)?;
-            println!("{}", String::from_utf8(buffer).unwrap());
+    // The handles a program starts with, found by applying its redirects left to right on top of any pipes
+    // 'stdin' is the read end of the pipe from the previous command, and the read end of a pipe to the next command is
+    // returned alongside. None means a file couldn't be opened and the failure has been reported.
+    fn open_streams(&mut self, source: &str, program: &Program, stdin: Option<PipeReader>) -> std::io::Result<Option<(Handles, Option<PipeReader>)>> {
+        let mut handles = Handles::from([(0, Handle::Inherit(0)), (1, Handle::Inherit(1)), (2, Handle::Inherit(2))]);
+        let mut reader = None;
+
+        if let Some(pipe) = stdin {
+            handles.insert(0, Handle::PipeIn(pipe));
         }
 
-        println!();
+        // The pipe is connected first, so a later redirect of stdout takes its place
+        if program.piped {
+            let (read, write) = std::io::pipe()?;
+            handles.insert(1, Handle::PipeOut(write));
+            reader = Some(read);
+        }
 
-        Ok(())
+        for redirect in &program.redirects {
+            let handle = match &redirect.strategy {
+                StreamStrategy::PipeFromFile(path) => self.open_input(source, path.clone()).map(Handle::File),
+                StreamStrategy::ReadWriteFile(path) => self.open_read_write(source, path.clone()).map(Handle::File),
+                StreamStrategy::PipeToFile(path, clobber) => self.create_output(source, path.clone(), *clobber).map(Handle::File),
+                StreamStrategy::AppendToFile(path) => self.append_output(source, path.clone()).map(Handle::File),
+                StreamStrategy::HereString(word) => Some(Handle::Text(expand::expand_word(self, span_text(source, word)) + "\n")),
+                StreamStrategy::HereDoc(body, true) => Some(Handle::Text(expand::expand_vars(self, span_text(source, body), true))),
+                StreamStrategy::HereDoc(body, false) => Some(Handle::Text(span_text(source, body).to_string())),
+                StreamStrategy::Discard => Some(Handle::Null),
+
+                // Sharing the handle rather than opening the file again keeps one offset, so appends don't overwrite each other
+                StreamStrategy::Duplicate(other) => match handles.get(other) {
+                    Some(handle) => Some(handle.try_clone()?),
+                    None => {
+                        self.report_closed_fd(source, redirect.span.clone(), *other);
+                        None
+                    }
+                }
+            };
+
+            let Some(handle) = handle else {
+                return Ok(None)
+            };
+
+            handles.insert(redirect.fd, handle);
+        }
+
+        Ok(Some((handles, reader)))
     }
 
-    fn clear(_: &mut crate::Engine, _: &Spanned<Program>) -> std::io::Result<()> {
-        std::io::stdout().flush().unwrap();
-        print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
+    fn report_closed_fd(&mut self, source: &str, span: Range<usize>, fd: usize) {
+        Report::build(ReportKind::Error, (self.source_name.as_str(), 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Bad file descriptor")
+            .with_label(
+                Label::new((self.source_name.as_str(), span))
+                    .with_message(format!("Descriptor {} isn't open here", fd))
+            )
+            .with_note("Only 0, 1, 2 and descriptors redirected earlier in the command can be duplicated")
+            .finish()
+            .print((self.source_name.as_str(), Source::from(source)))
+            .unwrap();
 
-        Ok(())
+        self.last_status = 1;
     }
 
-    fn exit(_: &mut crate::Engine, _: &Spanned<Program>) -> std::io::Result<()> {
-        std::process::exit(0);
+    // Opens the file a program reads stdin from, None means the failure has been reported
+    fn open_input(&mut self, source: &str, path: Range<usize>) -> Option<File> {
+        let target = self.resolve_path(span_text(source, &path));
+
+        if self.refuse_directory(source, &path, &target) {
+            return None
+        }
+
+        match File::open(target) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                self.report_redirect_error(source, path, err);
+                None
+            }
+        }
+    }
+
+    // Opens a file for '<>' without truncating it, creating it when it doesn't exist
+    fn open_read_write(&mut self, source: &str, path: Range<usize>) -> Option<File> {
+        let target = self.resolve_path(span_text(source, &path));
+
+        if self.refuse_directory(source, &path, &target) {
+            return None
+        }
+
+        match File::options().read(true).write(true).create(true).truncate(false).open(target) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                self.report_redirect_error(source, path, err);
+                None
+            }
+        }
+    }
+
+    // Creates the file a program writes stdout to, None means the failure has been reported
+    fn create_output(&mut self, source: &str, path: Range<usize>, clobber: bool) -> Option<File> {
+        let target = self.resolve_path(span_text(source, &path));
+
+        if self.refuse_directory(source, &path, &target) {
+            return None
+        }
+
+        if self.options.noclobber && !clobber && target.is_file() {
+            Report::build(ReportKind::Error, (self.source_name.as_str(), 0..0))
+                .with_config(Config::new().with_index_type(IndexType::Byte))
+                .with_message("Refusing to overwrite file")
+                .with_label(
+                    Label::new((self.source_name.as_str(), path))
+                        .with_message("This file already exists and noclobber is set")
+                )
+                .with_note("Use '>|' to overwrite it anyway")
+                .finish()
+                .print((self.source_name.as_str(), Source::from(source)))
+                .unwrap();
+
+            self.last_status = 1;
+            return None;
+        }
+
+        match File::create(target) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                self.report_redirect_error(source, path, err);
+                None
+            }
+        }
     }
-}
\ No newline at end of file
+
+    // Opens a file for '>>', which never truncates so noclobber doesn't apply
+    fn append_output(&mut self, source: &str, path: Range<usize>) -> Option<File> {
+        let target = self.resolve_path(span_text(source, &path));
+
+        if self.refuse_directory(source, &path, &target) {
+            return None
+        }
+
+        match File::options().append(true).create(true).open(target) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                self.report_redirect_error(source, path, err);
+                None
+            }
+        }
+    }
+
+    // A directory can't be redirected to or from, which is reported by name since opening one to read
+    // succeeds and would only fail once the program reads it
+    fn refuse_directory(&mut self, source: &str, path: &Range<usize>, target: &Path) -> bool {
+        if !target.is_dir() {
+            return false
+        }
+
+        Report::build(ReportKind::Error, (self.source_name.as_str(), 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Is a directory")
+            .with_label(
+                Label::new((self.source_name.as_str(), path.clone()))
+                    .with_message(format!("'{}' is a directory, not a file", span_text(source, path)))
+            )
+            .finish()
+            .print((self.source_name.as_str(), Source::from(source)))
+            .unwrap();
+
+        self.last_status = 1;
+        true
+    }
+
+    // The command isn't run when its redirect can't be opened
+    fn report_redirect_error(&mut self, source: &str, path: Range<usize>, err: std::io::Error) {
+        Report::build(ReportKind::Error, (self.source_name.as_str(), 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Unable to open file")
+            .with_label(
+                Label::new((self.source_name.as_str(), path))
+                    .with_message(err.to_string())
+            )
+            .finish()
+            .print((self.source_name.as_str(), Source::from(source)))
+            .unwrap();
+
+        self.last_status = 1;
+    }
+
+    // Where a builtin's output goes, following the statement's redirects
+    // None means a redirect was refused and has already been reported
+    fn builtin_stdout(&mut self, program: &Program) -> std::io::Result<Option<Box<dyn std::io::Write>>> {
+        let source = Rc::clone(&self.source);
+
+        let Some((mut handles, _)) = self.open_streams(&source, program, None)? else {
+            return Ok(None)
+        };
+
+        Ok(Some(match handles.remove(&1) {
+            Some(Handle::Inherit(2)) => Box::new(std::io::stderr()),
+            Some(Handle::Null) => Box::new(std::io::sink()),
+            Some(Handle::File(file)) => Box::new(file),
+            Some(Handle::PipeOut(pipe)) => Box::new(pipe),
+            _ if self.options.unbuffered => Box::new(Unbuffered(std::io::stdout())),
+            _ => Box::new(std::io::stdout())
+        }))
+    }
+
+    fn find_executable(&self, cmd: &str) -> Option<PathBuf> {
+        let names = Engine::executable_names(cmd);
+
+        // Anything containing a slash is a path to the executable rather than a name to search for
+        if cmd.contains('/') || cmd.contains(std::path::MAIN_SEPARATOR) {
+            return names.into_iter().map(|name| PathBuf::from(native_separators(&name))).find(|path| path.is_file());
+        }
+
+        for dir in &self.path {
+            for name in &names {
+                let full_path = PathBuf::from(dir).join(name);
+
+                if full_path.is_file() {
+                    return Some(full_path)
+                }
+            }
+        }
+
+        None
+    }
+
+    // The file names a command may have, Windows runs 'git' as 'git.exe' by trying each PATHEXT extension in order
+    // A name already ending in one of those extensions, such as 'git.exe', is tried as it is first
+    // Otherwise an extensionless file is skipped like in cmd.exe, since Windows can't run it
+    fn executable_names(cmd: &str) -> Vec<String> {
+        if cfg!(windows) {
+            let extensions = Engine::executable_extensions();
+            let typed = Path::new(cmd).extension().is_some_and(|ext| {
+                extensions.contains(&ext.to_string_lossy().to_ascii_lowercase())
+            });
+
+            typed.then(|| cmd.to_string())
+                .into_iter()
+                .chain(extensions.iter().map(|ext| format!("{}.{}", cmd, ext)))
+                .collect()
+        } else {
+            vec![cmd.to_string()]
+        }
+    }
+
+    // Extensions listed in PATHEXT in lowercase and without the leading '.'
+    fn executable_extensions() -> Vec<String> {
+        let pathext = env::var("PATHEXT").unwrap_or_else(|_| String::from(".COM;.EXE;.BAT;.CMD"));
+
+        pathext
+            .split(';')
+            .filter(|ext| !ext.is_empty())
+            .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+            .collect()
+    }
+
+    fn report_unknown_command(&self, source: &str, program: Range<usize>) {
+        let name = span_text(source, &program);
+        let mut report = Report::build(ReportKind::Error, (self.source_name.as_str(), 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Unknown command")
+            .with_label(
+                Label::new((self.source_name.as_str(), program))
+                    .with_message(format!("'{}' is not a builtin or a program on the PATH", name))
+            );
+
+        if let Some(suggestion) = self.suggest_command(name) {
+            report = report.with_note(format!("Did you mean '{}'?", suggestion));
+        }
+
+        report
+            .finish()
+            .print((self.source_name.as_str(), Source::from(source)))
+            .unwrap();
+    }
+
+    // Finds the builtin or PATH executable with the smallest edit distance to the given name
+    fn suggest_command(&self, name: &str) -> Option<String> {
+        let mut candidates: Vec<String> = self.builtins.keys().map(|b| b.to_string()).collect();
+
+        for dir in &self.path {
+            if let Ok(entries) = std::fs::read_dir(dir) {
+                candidates.extend(entries.flatten().filter_map(|entry| {
+                    let path = entry.path();
+                    let stem = if cfg!(windows) { path.file_stem() } else { path.file_name() };
+                    stem.and_then(|stem| stem.to_str()).map(|stem| stem.to_string())
+                }));
+            }
+        }
+
+        // Anything further away than a third of the name is unlikely to be a typo
+        let threshold = name.chars().count().div_ceil(3).max(1);
+
+        candidates
+            .into_iter()
+            .map(|candidate| (edit_distance(name, &candidate), candidate))
+            .filter(|(distance, _)| *distance <= threshold)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate)
+    }
+
+    // Changes the process working directory, keeping 'cur_dir' in step with it
+    // The path is resolved logically against 'cur_dir' so symlinks are preserved like other shells
+    fn change_dir(&mut self, path: &str) -> std::io::Result<()> {
+        let target = normalize_path(&Path::new(&self.cur_dir).join(native_separators(path)));
+
+        env::set_current_dir(&target)?;
+        let previous = std::mem::replace(&mut self.cur_dir, target.display().to_string());
+        self.vars.insert(String::from("OLDPWD"), previous);
+
+        Ok(())
+    }
+
+    // Refreshes 'cur_dir' from the process working directory in case a command changed or removed it
+    // 'cur_dir' is left alone while it still names the same directory so symlinks in it are preserved
+    fn sync_cwd(&mut self) {
+        match env::current_dir() {
+            Ok(real) => {
+                let same = Path::new(&self.cur_dir).canonicalize()
+                    .is_ok_and(|dir| real.canonicalize().is_ok_and(|real| real == dir));
+
+                if !same {
+                    self.cur_dir = real.display().to_string();
+                }
+            }
+
+            // The directory was deleted out from under the shell, so move to the closest directory still there
+            Err(_) => {
+                let fallback = Path::new(&self.cur_dir)
+                    .ancestors()
+                    .find(|dir| dir.is_dir())
+                    .map(|dir| dir.to_path_buf())
+                    .unwrap_or_else(|| PathBuf::from(Engine::get_base_dir()));
+
+                eprintln!(
+                    "Phoenix: warning: '{}' no longer exists, moving to '{}'",
+                    self.cur_dir,
+                    fallback.display()
+                );
+
+                if env::set_current_dir(&fallback).is_ok() {
+                    self.cur_dir = fallback.display().to_string();
+                }
+            }
+        }
+    }
+
+    // Expands a path-like word such as a redirect target, collapsing any '.' and '..' in absolute paths
+    // Relative paths are left for the OS to resolve against the process working directory, which is what
+    // programs see too, so 'link/../file' names the same file for a redirect as it does for 'cat'
+    fn resolve_path(&self, word: &str) -> PathBuf {
+        let path = PathBuf::from(native_separators(&expand::expand_word(self, word)));
+
+        if path.is_relative() {
+            return path
+        }
+
+        normalize_path(&path)
+    }
+
+    // Substitutes a history reference at the start of a line, such as '!!', '!3', '!-2' or '!make'
+    // The expanded command is echoed like other shells, returns None if the referenced entry doesn't exist
+    pub fn expand_history(&mut self, line: &str) -> Option<String> {
+        let Some(reference) = line.trim_start().strip_prefix('!') else {
+            return Some(line.to_string())
+        };
+
+        let end = reference.find(char::is_whitespace).unwrap_or(reference.len());
+        let (event, rest) = reference.split_at(end);
+
+        if event.is_empty() {
+            return Some(line.to_string())
+        }
+
+        let entry = match event {
+            "!" => self.history.last(),
+            _ => match event.parse::<isize>() {
+                Ok(n) if n > 0 => self.history.get(n as usize - 1),
+                Ok(n) if n < 0 => self.history.len().checked_sub(n.unsigned_abs()).and_then(|i| self.history.get(i)),
+                Ok(_) => None,
+                Err(_) => self.history.iter().rev().find(|entry| entry.starts_with(event))
+            }
+        };
+
+        let Some(entry) = entry else {
+            eprintln!("Phoenix: !{}: event not found", event);
+            self.last_status = 1;
+            return None
+        };
+
+        let expanded = format!("{}{}", entry, rest.trim_end());
+        println!("{}", expanded);
+
+        Some(expanded)
+    }
+
+    // Lines entered at the prompt, oldest first
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    // Records a line entered at the prompt, blank lines aren't worth recalling
+    pub fn add_history(&mut self, line: &str) {
+        let line = line.trim_end();
+
+        if !line.trim_start().is_empty() {
+            self.history.push(line.to_string());
+        }
+    }
+
+    // Offers what the word before the cursor can complete to when Tab is pressed at the prompt
+    // After a '$' that's the names of variables, which include the environment Phoenix was started with
+    pub fn complete(&self, line: &str, cursor: usize) -> Option<Completion> {
+        self.complete_var(line, cursor).or_else(|| self.complete_argument(line, cursor))
+    }
+
+    fn complete_var(&self, line: &str, cursor: usize) -> Option<Completion> {
+        let before = &line[..cursor];
+        let dollar = before.rfind('$')?;
+        let partial = &before[dollar + 1..];
+        let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+        let (braced, name) = match partial.strip_prefix('{') {
+            Some(name) => (true, name),
+            None => (false, partial)
+        };
+
+        if !name.chars().all(is_name_char) || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return None
+        }
+
+        // A name continuing after the cursor would run into the completed one, so braces keep them apart
+        let after = &line[cursor..];
+        let braced = braced || after.starts_with(is_name_char);
+        let close = if after.starts_with('}') { "" } else { "}" };
+
+        let mut candidates: Vec<String> = self.vars.keys().map(String::as_str).chain(DYNAMIC_VARS)
+            .filter(|var| var.starts_with(name))
+            .map(|var| if braced { format!("${{{}{}", var, close) } else { format!("${}", var) })
+            .collect();
+
+        candidates.sort();
+        candidates.dedup();
+        Some(Completion { start: dollar, candidates })
+    }
+
+    // Completes an argument from the words registered for the command with 'complete'
+    fn complete_argument(&self, line: &str, cursor: usize) -> Option<Completion> {
+        let before = &line[..cursor];
+
+        // The command is the first word since the previous statement or pipe, and the word at the cursor comes after it
+        let stage = before.rfind(['|', ';', '&', '\n']).map_or(0, |index| index + 1);
+        let start = before.rfind([' ', '\t']).map_or(0, |index| index + 1).max(stage);
+        let command = before[stage..start].split_whitespace().next()?;
+        let partial = &before[start..];
+
+        let mut candidates: Vec<String> = self.completions.get(command)?.iter()
+            .filter(|word| word.starts_with(partial))
+            .cloned()
+            .collect();
+
+        candidates.sort();
+        candidates.dedup();
+        Some(Completion { start, candidates })
+    }
+
+    // What a word typed first at the prompt expands to, if it's an abbreviation
+    pub fn abbreviation(&self, word: &str) -> Option<String> {
+        self.abbreviations.get(word).cloned()
+    }
+
+    // The '>' turns red after a command fails, when stdout is a terminal that understands colors
+    pub fn prompt(&self) -> String {
+        let dir = self.display_dir(&normalize_path(Path::new(&self.cur_dir)));
+
+        if self.last_status != 0 && self.use_color() {
+            return format!("{}\x1b[31m>\x1b[0m", dir)
+        }
+
+        format!("{}>", dir)
+    }
+
+    // Shortens a directory under the home directory to start with '~', unless 'prompttilde' is turned off
+    // Whole components are compared, so '/home/user2' isn't shortened when the home directory is '/home/user'
+    fn display_dir(&self, dir: &Path) -> String {
+        let home = self.lookup_var("HOME").filter(|home| self.options.prompttilde && !home.is_empty());
+
+        match home.as_deref().and_then(|home| dir.strip_prefix(home).ok()) {
+            Some(rest) if rest.as_os_str().is_empty() => String::from("~"),
+            Some(rest) => format!("~{}{}", std::path::MAIN_SEPARATOR, rest.display()),
+            None => dir.display().to_string()
+        }
+    }
+
+    // Colors are left out when 'NO_COLOR' is set to anything, following https://no-color.org
+    fn use_color(&self) -> bool {
+        self.lookup_var("NO_COLOR").is_none_or(|value| value.is_empty())
+            && std::io::IsTerminal::is_terminal(&std::io::stdout())
+            && builtins::supports_ansi()
+    }
+
+    pub fn get_base_dir() -> String {
+        env::var_os(PLATFORM_VARS::BASE_DIR).unwrap().into_string().unwrap()
+    }
+}
+
+// Seeds '$RANDOM' so each shell produces a different sequence
+fn random_seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64);
+
+    (nanos ^ ((std::process::id() as u64) << 32)) | 1
+}
+
+// Reads one line from stdin without reading past it, so the rest of piped input is left for the commands being run
+// Piped input is read a byte at a time like other shells do, a terminal already delivers a line per read
+#[cfg(target_os = "linux")]
+pub fn read_stdin_line(line: &mut String) -> std::io::Result<usize> {
+    if std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        return std::io::stdin().read_line(line)
+    }
+
+    let mut bytes = Vec::new();
+    let mut byte = 0u8;
+
+    while bytes.last() != Some(&b'\n') {
+        // Safety: 'byte' is a valid buffer for the single byte being read
+        let read = unsafe { libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) };
+
+        match read {
+            0 => break,
+            1 => bytes.push(byte),
+            _ => {
+                let err = std::io::Error::last_os_error();
+
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err)
+                }
+            }
+        }
+    }
+
+    line.push_str(&String::from_utf8_lossy(&bytes));
+    Ok(bytes.len())
+}
+
+// Programs started from Windows may miss piped input which was read ahead into the buffer
+#[cfg(target_os = "windows")]
+pub fn read_stdin_line(line: &mut String) -> std::io::Result<usize> {
+    std::io::stdin().read_line(line)
+}
+
+// What each of a program's descriptors is connected to, always including 0, 1 and 2
+type Handles = std::collections::BTreeMap<usize, Handle>;
+
+// What one of a program's descriptors is connected to, kept as a handle until spawning so '>&' can duplicate it
+enum Handle {
+    Inherit(usize), // Phoenix's own descriptor with this number
+    Null,
+    File(File),
+    PipeIn(PipeReader),
+    PipeOut(PipeWriter),
+    Text(String) // Written to the program's stdin by the shell once it's spawned
+}
+
+impl Handle {
+    // The Stdio for the program's descriptor 'fd'
+    fn into_stdio(self, fd: usize) -> std::io::Result<Stdio> {
+        Ok(match self {
+            Handle::Inherit(own) if own == fd => Stdio::inherit(),
+            // Such as stderr after '2>&1' when stdout isn't redirected
+            Handle::Inherit(own) => duplicate_std(own)?,
+            Handle::Null => Stdio::null(),
+            Handle::File(file) => Stdio::from(file),
+            Handle::PipeIn(pipe) => Stdio::from(pipe),
+            Handle::PipeOut(pipe) => Stdio::from(pipe),
+            Handle::Text(_) => Stdio::piped()
+        })
+    }
+
+    // The descriptor to give a program as one of its descriptors above 2
+    #[cfg(target_os = "linux")]
+    fn into_fd(self) -> std::io::Result<std::os::fd::OwnedFd> {
+        use std::os::fd::AsFd;
+
+        Ok(match self {
+            Handle::Inherit(0) => std::io::stdin().as_fd().try_clone_to_owned()?,
+            Handle::Inherit(1) => std::io::stdout().as_fd().try_clone_to_owned()?,
+            Handle::Inherit(_) => std::io::stderr().as_fd().try_clone_to_owned()?,
+            Handle::Null => File::options().read(true).write(true).open("/dev/null")?.into(),
+            Handle::File(file) => file.into(),
+            Handle::PipeIn(pipe) => pipe.into(),
+            Handle::PipeOut(pipe) => pipe.into(),
+
+            // Such as '3>&0' after '<<<', where there's no pipe for the shell to write to yet
+            Handle::Text(_) => return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "a here-document can only be read from stdin"))
+        })
+    }
+
+    // A second handle to the same place, which shares the file offset like a duplicated descriptor does
+    fn try_clone(&self) -> std::io::Result<Handle> {
+        Ok(match self {
+            Handle::Inherit(fd) => Handle::Inherit(*fd),
+            Handle::Null => Handle::Null,
+            Handle::File(file) => Handle::File(file.try_clone()?),
+            Handle::PipeIn(pipe) => Handle::PipeIn(pipe.try_clone()?),
+            Handle::PipeOut(pipe) => Handle::PipeOut(pipe.try_clone()?),
+            Handle::Text(text) => Handle::Text(text.clone())
+        })
+    }
+}
+
+// Connects a command to its handles, returning the text the shell has to write to its stdin once it's spawned
+fn attach_streams(cmd: &mut Command, mut handles: Handles) -> std::io::Result<Option<String>> {
+    let (Some(stdin), Some(stdout), Some(stderr)) = (handles.remove(&0), handles.remove(&1), handles.remove(&2)) else {
+        unreachable!("stdin, stdout and stderr always have a handle")
+    };
+
+    let input = match &stdin {
+        Handle::Text(text) => Some(text.clone()),
+        _ => None
+    };
+
+    cmd.stdin(stdin.into_stdio(0)?);
+    cmd.stdout(stdout.into_stdio(1)?);
+    cmd.stderr(stderr.into_stdio(2)?);
+
+    attach_extra_fds(cmd, handles)?;
+    Ok(input)
+}
+
+// Descriptors above 2 are moved into place in the child between fork and exec
+#[cfg(target_os = "linux")]
+fn attach_extra_fds(cmd: &mut Command, handles: Handles) -> std::io::Result<()> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::process::CommandExt;
+
+    let Some(highest) = handles.keys().next_back().copied() else {
+        return Ok(())
+    };
+
+    let mut fds = Vec::new();
+
+    for (target, handle) in handles {
+        let fd = handle.into_fd()?;
+
+        // A copy above every target can't be overwritten by moving another descriptor into place
+        // Safety: 'fd' is open, and F_DUPFD_CLOEXEC returns a new descriptor which is owned here
+        let moved = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, highest as libc::c_int + 1) };
+
+        if moved < 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+
+        // Safety: 'moved' was just returned by fcntl and nothing else owns it
+        fds.push((unsafe { OwnedFd::from_raw_fd(moved) }, target as libc::c_int));
+    }
+
+    // Safety: only dup2 is called, which is async-signal-safe, and 'fds' is kept open until the child has started
+    unsafe {
+        cmd.pre_exec(move || {
+            for (fd, target) in &fds {
+                if libc::dup2(fd.as_raw_fd(), *target) < 0 {
+                    return Err(std::io::Error::last_os_error())
+                }
+            }
+
+            Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+// The parser refuses descriptors above 2 on Windows, so there are never any to attach
+#[cfg(target_os = "windows")]
+fn attach_extra_fds(_: &mut Command, _: Handles) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Another handle to one of Phoenix's own descriptors
+#[cfg(target_os = "linux")]
+fn duplicate_std(fd: usize) -> std::io::Result<Stdio> {
+    use std::os::fd::AsFd;
+
+    let handle = match fd {
+        0 => std::io::stdin().as_fd().try_clone_to_owned()?,
+        1 => std::io::stdout().as_fd().try_clone_to_owned()?,
+        _ => std::io::stderr().as_fd().try_clone_to_owned()?
+    };
+
+    Ok(Stdio::from(handle))
+}
+
+#[cfg(target_os = "windows")]
+fn duplicate_std(fd: usize) -> std::io::Result<Stdio> {
+    use std::os::windows::io::AsHandle;
+
+    let handle = match fd {
+        0 => std::io::stdin().as_handle().try_clone_to_owned()?,
+        1 => std::io::stdout().as_handle().try_clone_to_owned()?,
+        _ => std::io::stderr().as_handle().try_clone_to_owned()?
+    };
+
+    Ok(Stdio::from(handle))
+}
+
+// Passes every write straight through for 'set -o unbuffered', so even part of a line shows up as soon as it's written
+struct Unbuffered<W>(W);
+
+impl<W: std::io::Write> std::io::Write for Unbuffered<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.0.write(buf)?;
+        self.0.flush()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Writes the text of a here-document or here-string to a child's stdin
+// This happens on another thread so a child that fills its own output pipe before reading can't deadlock the shell
+fn feed_stdin(child: &mut Child, input: Option<String>) {
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        std::thread::spawn(move || {
+            // The child may exit without reading everything, which isn't an error for the shell
+            let _ = std::io::Write::write_all(&mut stdin, input.as_bytes());
+        });
+    }
+}
+
+// Converts a child's exit status into a shell status, with signals following the 128 + n convention
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+
+    status.code().unwrap_or(1)
+}
+
+// Windows accepts '/' as well, but paths are resolved and displayed with the native separator
+fn native_separators(path: &str) -> String {
+    if cfg!(windows) {
+        path.replace('/', "\\")
+    } else {
+        path.to_string()
+    }
+}
+
+// Collapses '.' and '..' components without touching the filesystem, so the path needn't exist
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match result.components().next_back() {
+                Some(Component::Normal(_)) => { result.pop(); }
+
+                // There is nothing above the root
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+
+                // Leading '..' of a relative path can't be collapsed
+                _ => result.push("..")
+            }
+            component => result.push(component)
+        }
+    }
+
+    if result.as_os_str().is_empty() {
+        result.push(".");
+    }
+
+    result
+}
+
+// Levenshtein distance between two strings, used for command suggestions
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+
+        prev = cur;
+    }
+
+    prev[b.len()]
+}
+
+// TODO: Finish implementing builtins module
+mod builtins {
+    use std::{collections::HashMap, env, io::{Read, Write}, ops::Range, path::Path, rc::Rc};
+    use ariadne::{Config, IndexType, Label, Report, ReportKind, Source};
+    use crate::{ast::{span_text, Program, Spanned}, engine::{Engine, Handle, Handles, Job, attach_streams, edit_distance, native_separators}, expand, input_lexer::InputLexer};
+
+    pub type BuiltinFn = fn(&mut crate::Engine, &Spanned<Program>) -> std::io::Result<()>;
+
+    // A builtin along with the text 'help' shows for it
+    #[derive(Clone, Copy)]
+    pub struct Builtin {
+        pub run: BuiltinFn,
+        pub summary: &'static str, // One line shown in the list of builtins
+        pub usage: &'static str // Synopsis followed by a description, shown by 'help NAME'
+    }
+
+    // Reports a usage error for a builtin and marks it as failed
+    fn error(engine: &mut Engine, span: Range<usize>, message: &str, label: String) {
+        Report::build(ReportKind::Error, (engine.source_name.as_str(), 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message(message)
+            .with_label(Label::new((engine.source_name.as_str(), span)).with_message(label))
+            .finish()
+            .eprint((engine.source_name.as_str(), Source::from(&*engine.source)))
+            .unwrap();
+
+        engine.last_status = 2;
+    }
+
+    // Each builtin is declared next to its function along with its help text, aliases share an entry
+    pub fn builtin_registry() -> HashMap<&'static str, Builtin> {
+        HashMap::from([
+            ("cd", CD),
+            ("ls", LS),
+            ("echo", ECHO),
+            ("clear", CLEAR),
+            ("reset", RESET),
+            ("exit", EXIT),
+            ("exec", EXEC),
+            ("read", READ),
+            ("set", SET),
+            ("wait", WAIT),
+            ("jobs", JOBS),
+            ("disown", DISOWN),
+            ("kill", KILL),
+            ("source", SOURCE),
+            (".", SOURCE),
+            ("pushd", PUSHD),
+            ("popd", POPD),
+            ("dirs", DIRS),
+            ("export", EXPORT),
+            ("declare", DECLARE),
+            ("unset", UNSET),
+            ("command", COMMAND),
+            ("true", TRUE),
+            ("false", FALSE),
+            ("test", TEST),
+            ("[", TEST),
+            ("printf", PRINTF),
+            ("repeat", REPEAT),
+            ("time", TIME),
+            ("timeout", TIMEOUT),
+            ("env", ENV),
+            ("help", HELP),
+            ("alias", ALIAS),
+            ("abbr", ABBR),
+            ("complete", COMPLETE),
+            ("basename", BASENAME),
+            ("dirname", DIRNAME),
+            ("yes", YES)
+        ])
+    }
+
+    const CD: Builtin = Builtin {
+        run: cd,
+        summary: "Change the current directory",
+        usage: "cd [--] [DIR]\n\nChanges to DIR, or to the home directory when DIR is omitted. 'cd -' returns to the previous directory.\nEverything after '--' is a directory, even if it starts with '-'."
+    };
+
+    fn cd(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        // TODO: Lexer crashes with no token implementation of 'cd ..\'
+        // cd has no options, so any other argument starting with '-' such as '-weird' is a directory
+        let index = usize::from(engine.args.first().is_some_and(|arg| arg == "--"));
+        let span = stmt.value.argv.get(index).unwrap_or(&stmt.value.program).clone();
+
+        let target = match engine.args.get(index).map(|arg| arg.as_str()) {
+            None => Engine::get_base_dir(),
+
+            // Only a lone '-' before any '--' returns to the previous directory
+            Some("-") if index == 0 => {
+                let Some(oldpwd) = engine.lookup_var("OLDPWD") else {
+                    error(engine, span, "No previous directory", String::from("OLDPWD is not set"));
+                    engine.last_status = 1;
+                    return Ok(())
+                };
+
+                println!("{}", oldpwd);
+                oldpwd
+            }
+
+            Some(path) => path.to_string()
+        };
+
+        if let Err(err) = engine.change_dir(&target) {
+            error(engine, span, "Unable to change directory", format!("{}: {}", target, err));
+            engine.last_status = 1;
+            return Ok(())
+        }
+
+        Ok(())
+    }
+
+    const LS: Builtin = Builtin {
+        run: ls,
+        summary: "List the files in the current directory",
+        usage: "ls\n\nPrints the name of every entry in the current directory."
+    };
+
+    // Output is written with 'writeln!' rather than 'println!' so a closed stdout is returned as an error instead of panicking
+    fn ls(_: &mut crate::Engine, _: &Spanned<Program>) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+
+        // The process working directory is read rather than 'cur_dir' so this lists what programs would see
+        for entry in std::fs::read_dir(".").unwrap() {
+            writeln!(stdout, "{}", entry.unwrap().file_name().display())?;
+        }
+
+        writeln!(stdout)
+    }
+
+    const ECHO: Builtin = Builtin {
+        run: echo,
+        summary: "Print arguments",
+        usage: "echo [ARG...]\n\nPrints the arguments separated by spaces. Without arguments stdin is copied to stdout."
+    };
+
+    fn echo(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+
+        if !stmt.value.argv.is_empty() {
+            let content = engine.args.join(" ");
+            writeln!(stdout, "{}", content)?;
+        } else {
+            // TODO: We don't support piping for internals
+            // could we possibly change that to support piping *to* internals at least
+            let mut buffer = Vec::new();
+            std::io::stdin().read_to_end(&mut buffer)?;
+            writeln!(stdout, "{}", String::from_utf8(buffer).unwrap())?;
+        }
+
+        writeln!(stdout)
+    }
+
+    const CLEAR: Builtin = Builtin {
+        run: clear,
+        summary: "Clear the terminal",
+        usage: "clear\n\nClears the screen and moves the cursor to the top left corner."
+    };
+
+    fn clear(_: &mut crate::Engine, _: &Spanned<Program>) -> std::io::Result<()> {
+        if supports_ansi() {
+            print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
+        } else {
+            // Let the platform's own command deal with terminals that don't understand escape codes
+            #[cfg(target_os = "windows")]
+            std::process::Command::new("cmd").args(["/C", "cls"]).status()?;
+            #[cfg(target_os = "linux")]
+            std::process::Command::new("clear").status()?;
+        }
+
+        Ok(())
+    }
+
+    const RESET: Builtin = Builtin {
+        run: reset,
+        summary: "Reset a garbled terminal",
+        usage: "reset\n\nResets the terminal, clearing it and turning colors, the cursor and line editing back to normal.\nUseful after a full-screen program exits without cleaning up."
+    };
+
+    fn reset(_: &mut crate::Engine, _: &Spanned<Program>) -> std::io::Result<()> {
+        // A full reset, then a soft reset and the cursor and colors for terminals which only partly honor it
+        if supports_ansi() {
+            print!("{esc}c{esc}[!p{esc}[?25h{esc}[0m", esc = 27 as char);
+        }
+
+        restore_terminal_modes()
+    }
+
+    // Puts back the settings a program may have changed, like 'stty sane', so typing echoes and Enter ends a line
+    #[cfg(target_os = "linux")]
+    fn restore_terminal_modes() -> std::io::Result<()> {
+        if !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+            return Ok(())
+        }
+
+        // Safety: termios is plain data which 'tcgetattr' fills in before it's read
+        let mut modes: libc::termios = unsafe { std::mem::zeroed() };
+
+        // Safety: 'modes' is a valid termios for the call to write to
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut modes) } != 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+
+        modes.c_lflag |= libc::ICANON | libc::ECHO | libc::ECHOE | libc::ECHOK | libc::ISIG | libc::IEXTEN;
+        modes.c_iflag |= libc::ICRNL | libc::IXON | libc::BRKINT;
+        modes.c_iflag &= !(libc::INLCR | libc::IGNCR);
+        modes.c_oflag |= libc::OPOST | libc::ONLCR;
+        modes.c_cc[libc::VMIN] = 1;
+        modes.c_cc[libc::VTIME] = 0;
+
+        // Safety: 'modes' holds the terminal's own settings with the usual flags turned back on
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &modes) } != 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+
+        Ok(())
+    }
+
+    // The console restores its own input modes when a program exits, so only the screen is reset
+    #[cfg(target_os = "windows")]
+    fn restore_terminal_modes() -> std::io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn supports_ansi() -> bool {
+        use std::ffi::c_void;
+
+        const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+        const ENABLE_VIRTUAL_TERMINAL_PROCESSING: u32 = 0x0004;
+
+        #[link(name = "kernel32")]
+        unsafe extern "system" {
+            fn GetStdHandle(handle: u32) -> *mut c_void;
+            fn GetConsoleMode(handle: *mut c_void, mode: *mut u32) -> i32;
+            fn SetConsoleMode(handle: *mut c_void, mode: u32) -> i32;
+        }
+
+        // Legacy consoles refuse to enable virtual terminal processing
+        unsafe {
+            let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+            let mut mode = 0;
+
+            GetConsoleMode(handle, &mut mode) != 0
+                && (mode & ENABLE_VIRTUAL_TERMINAL_PROCESSING != 0
+                    || SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn supports_ansi() -> bool {
+        env::var("TERM").map(|term| term != "dumb").unwrap_or(false)
+    }
+
+    const EXIT: Builtin = Builtin {
+        run: exit,
+        summary: "Exit the shell",
+        usage: "exit\n\nExits Phoenix."
+    };
+
+    fn exit(_: &mut crate::Engine, _: &Spanned<Program>) -> std::io::Result<()> {
+        std::process::exit(0);
+    }
+
+    const EXEC: Builtin = Builtin {
+        run: exec,
+        summary: "Replace the shell with a program, or redirect the shell itself",
+        usage: "exec [--] [PROGRAM [ARG...]]\n\nRuns PROGRAM in place of the shell, which doesn't come back once it exits.\nWithout a PROGRAM the redirects apply to the shell, such as 'exec 2>log' sending every later error to 'log'.\nWindows can't replace a process, so there PROGRAM runs as a child and the shell exits with its status."
+    };
+
+    fn exec(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let source = Rc::clone(&engine.source);
+
+        let Some((inner, args)) = nested_command(engine, stmt, 0) else {
+            let Some((handles, _)) = engine.open_streams(&source, &stmt.value, None)? else {
+                return Ok(())
+            };
+
+            return redirect_shell(handles)
+        };
+
+        // Builtins can't replace the shell, so only programs are looked for
+        let Some(executable) = engine.find_executable(inner.value.program_str(&source)) else {
+            engine.report_unknown_command(&source, inner.value.program);
+            engine.last_status = 127;
+            return Ok(())
+        };
+
+        let cmd = engine.new_command(&source, executable, args, &inner.value);
+
+        let Some((handles, _)) = engine.open_streams(&source, &inner.value, None)? else {
+            return Ok(())
+        };
+
+        engine.log_spawn(&cmd, &inner.value);
+
+        // Output the shell buffered would otherwise be lost along with the process
+        std::io::stdout().flush()?;
+        replace_process(cmd, handles)
+    }
+
+    // Only returns when the program couldn't be started, in which case the shell carries on
+    #[cfg(target_os = "linux")]
+    fn replace_process(mut cmd: std::process::Command, mut handles: Handles) -> std::io::Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        // There's no shell left to write a here-document to a pipe, so the program reads it from a file instead
+        if let Some(Handle::Text(text)) = handles.get(&0) {
+            handles.insert(0, Handle::File(text_file(text)?));
+        }
+
+        attach_streams(&mut cmd, handles)?;
+        Err(cmd.exec())
+    }
+
+    #[cfg(target_os = "windows")]
+    fn replace_process(mut cmd: std::process::Command, handles: Handles) -> std::io::Result<()> {
+        let input = attach_streams(&mut cmd, handles)?;
+        let mut child = cmd.spawn()?;
+
+        super::feed_stdin(&mut child, input);
+        std::process::exit(super::exit_code(child.wait()?))
+    }
+
+    // Points the shell's own descriptors where the redirects say, for every command run after this one
+    #[cfg(target_os = "linux")]
+    fn redirect_shell(handles: Handles) -> std::io::Result<()> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        let Some(highest) = handles.keys().next_back().copied() else {
+            return Ok(())
+        };
+
+        // Every handle is taken before any descriptor moves, so '2>&1' still means the stdout from before the redirects
+        let mut fds = Vec::new();
+
+        for (target, handle) in handles {
+            let fd = match handle {
+                Handle::Inherit(own) if own == target => continue,
+                Handle::Text(text) => OwnedFd::from(text_file(&text)?),
+                handle => handle.into_fd()?
+            };
+
+            // A copy above every target can't be closed by moving another descriptor into place
+            // Safety: 'fd' is open, and F_DUPFD_CLOEXEC returns a new descriptor which is owned here
+            let moved = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, highest as libc::c_int + 1) };
+
+            if moved < 0 {
+                return Err(std::io::Error::last_os_error())
+            }
+
+            // Safety: 'moved' was just returned by fcntl and nothing else owns it
+            fds.push((unsafe { OwnedFd::from_raw_fd(moved) }, target as libc::c_int));
+        }
+
+        // Output written before the redirect belongs to the old stdout
+        std::io::stdout().flush()?;
+
+        for (fd, target) in fds {
+            // Safety: both descriptors are valid, dup2 closes 'target' first if it was open
+            if unsafe { libc::dup2(fd.as_raw_fd(), target) } < 0 {
+                return Err(std::io::Error::last_os_error())
+            }
+        }
+
+        Ok(())
+    }
+
+    // The standard handles are swapped out, which Rust's stdin, stdout and stderr look up on every use
+    #[cfg(target_os = "windows")]
+    fn redirect_shell(handles: Handles) -> std::io::Result<()> {
+        use std::ffi::c_void;
+        use std::os::windows::io::{AsHandle, IntoRawHandle, OwnedHandle};
+
+        const STD_HANDLES: [u32; 3] = [-10i32 as u32, -11i32 as u32, -12i32 as u32];
+
+        #[link(name = "kernel32")]
+        unsafe extern "system" {
+            fn SetStdHandle(which: u32, handle: *mut c_void) -> i32;
+        }
+
+        let mut replaced = Vec::new();
+
+        for (target, handle) in handles {
+            let handle: OwnedHandle = match handle {
+                Handle::Inherit(own) if own == target => continue,
+                Handle::Inherit(0) => std::io::stdin().as_handle().try_clone_to_owned()?,
+                Handle::Inherit(1) => std::io::stdout().as_handle().try_clone_to_owned()?,
+                Handle::Inherit(_) => std::io::stderr().as_handle().try_clone_to_owned()?,
+                Handle::Null => std::fs::File::options().read(true).write(true).open("NUL")?.into(),
+                Handle::File(file) => file.into(),
+                Handle::PipeIn(pipe) => pipe.into(),
+                Handle::PipeOut(pipe) => pipe.into(),
+
+                // The shell outlives the redirect here, so a thread can write the text as it's read
+                Handle::Text(text) => {
+                    let (read, mut write) = std::io::pipe()?;
+                    std::thread::spawn(move || write.write_all(text.as_bytes()));
+                    read.into()
+                }
+            };
+
+            replaced.push((STD_HANDLES[target], handle));
+        }
+
+        std::io::stdout().flush()?;
+
+        for (which, handle) in replaced {
+            // Safety: the handle is open and ownership passes to the process's standard handles
+            if unsafe { SetStdHandle(which, handle.into_raw_handle()) } == 0 {
+                return Err(std::io::Error::last_os_error())
+            }
+        }
+
+        Ok(())
+    }
+
+    // An unnamed file holding a here-document, read from the start like the pipe it stands in for
+    #[cfg(target_os = "linux")]
+    fn text_file(text: &str) -> std::io::Result<std::fs::File> {
+        use std::io::Seek;
+        use std::os::fd::FromRawFd;
+
+        // Safety: the name is a valid C string
+        let fd = unsafe { libc::memfd_create(c"here-document".as_ptr(), libc::MFD_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+
+        // Safety: 'fd' was just returned by memfd_create and nothing else owns it
+        let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+        file.write_all(text.as_bytes())?;
+        file.rewind()?;
+
+        Ok(file)
+    }
+
+    const READ: Builtin = Builtin {
+        run: read,
+        summary: "Read a line from stdin into variables",
+        usage: "read [-p PROMPT] [NAME...]\n\nReads a line and splits it into words, assigning one to each NAME with the rest going to the last.\nThe line is stored in REPLY when no names are given. '-p' prints PROMPT first."
+    };
+
+    fn read(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let argv = stmt.value.argv_strs(&engine.source);
+        let mut args = argv.into_iter();
+        let mut names = Vec::new();
+
+        while let Some(arg) = args.next() {
+            if arg == "-p" {
+                if let Some(prompt) = args.next() {
+                    print!("{}", expand::expand_word(engine, prompt));
+                    std::io::stdout().flush()?;
+                }
+            } else {
+                names.push(arg.to_string());
+            }
+        }
+
+        // Like other shells, the line goes to 'REPLY' if no names are given
+        if names.is_empty() {
+            names.push(String::from("REPLY"));
+        }
+
+        let mut line = String::new();
+        crate::engine::read_stdin_line(&mut line)?;
+        let mut rest = line.trim_end_matches(['\n', '\r']).trim_start();
+
+        // Each name takes one word, with the last name taking whatever remains of the line
+        for (i, name) in names.iter().enumerate() {
+            let value = if i == names.len() - 1 {
+                rest.trim_end()
+            } else {
+                let (word, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                rest = remainder.trim_start();
+                word
+            };
+
+            engine.vars.insert(name.clone(), value.to_string());
+        }
+
+        Ok(())
+    }
+
+    const SET: Builtin = Builtin {
+        run: set,
+        summary: "Change or list shell options",
+        usage: "set [-e|+e] [-x|+x] [-C|+C] [-o NAME|+o NAME]... [-o maxpipeline=N]\n\nA '-' enables an option and a '+' disables it. Without arguments every option is listed.\nOptions: errexit (-e), xtrace (-x), noclobber (-C), pipefail, prompttilde and unbuffered.\nThe 'maxpipeline' option takes a value instead, the most commands a pipeline may have (256 by default)."
+    };
+
+    fn set(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        if stmt.value.argv.is_empty() {
+            for (name, enabled) in engine.options.list() {
+                println!("{:<12}{}", name, if enabled { "on" } else { "off" });
+            }
+
+            println!("{:<12}{}", "maxpipeline", engine.options.max_pipeline);
+
+            return Ok(())
+        }
+
+        let mut args = stmt.value.argv.iter();
+
+        while let Some(arg) = args.next() {
+            let text = span_text(&engine.source, arg).to_string();
+
+            // '-' enables an option and '+' disables it
+            let enable = match text.chars().next() {
+                Some('-') => true,
+                Some('+') => false,
+                _ => {
+                    error(engine, arg.clone(), "Invalid option", String::from("Options must start with '-' or '+'"));
+                    return Ok(())
+                }
+            };
+
+            if &text[1..] == "o" {
+                let Some(name) = args.next() else {
+                    error(engine, arg.clone(), "Missing option name", String::from("Expected an option name such as 'pipefail' after this"));
+                    return Ok(())
+                };
+
+                // The one option with a value rather than on or off
+                if let Some(value) = span_text(&engine.source, name).strip_prefix("maxpipeline=") {
+                    match value.parse::<usize>() {
+                        Ok(limit) if limit > 0 => engine.options.max_pipeline = limit,
+                        _ => {
+                            error(engine, name.clone(), "Invalid option value", String::from("Expected a pipeline length of at least 1"));
+                            return Ok(())
+                        }
+                    }
+
+                    continue;
+                }
+
+                match engine.options.by_name(span_text(&engine.source, name)) {
+                    Some(option) => *option = enable,
+                    None => {
+                        error(engine, name.clone(), "Invalid option", String::from("Unknown option name"));
+                        return Ok(())
+                    }
+                }
+
+                continue;
+            }
+
+            // Flags can be combined such as 'set -ex'
+            for flag in text[1..].chars() {
+                match engine.options.by_flag(flag) {
+                    Some(option) => *option = enable,
+                    None => {
+                        error(engine, arg.clone(), "Invalid option", format!("Unknown option '{}'", flag));
+                        return Ok(())
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    const WAIT: Builtin = Builtin {
+        run: wait,
+        summary: "Wait for background jobs to finish",
+        usage: "wait [JOB...]\n\nWaits for each JOB, written as '%1' or '1', or for every job when none are given.\nThe status is that of the last job waited for."
+    };
+
+    fn wait(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        if stmt.value.argv.is_empty() {
+            for job in std::mem::take(&mut engine.jobs) {
+                engine.last_status = engine.wait_children(job.children)?;
+            }
+
+            return Ok(())
+        }
+
+        for arg in &stmt.value.argv {
+            // Jobs can be referred to as either '%1' or '1'
+            let id = span_text(&engine.source, arg).trim_start_matches('%').parse::<usize>().ok();
+
+            let Some(index) = engine.jobs.iter().position(|job| Some(job.id) == id) else {
+                error(engine, arg.clone(), "Unknown job", String::from("There is no background job with this id"));
+                engine.last_status = 127;
+                return Ok(())
+            };
+
+            let job = engine.jobs.remove(index);
+            engine.last_status = engine.wait_children(job.children)?;
+        }
+
+        Ok(())
+    }
+
+    const JOBS: Builtin = Builtin {
+        run: jobs,
+        summary: "List background jobs",
+        usage: "jobs [-l]\n\nLists the background jobs which are still running, marking the latest with '+' and the one before it with '-'.\nJobs which have finished are reported and removed first. With '-l' the process ids are included."
+    };
+
+    fn jobs(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let mut pids = false;
+
+        for (arg, span) in engine.args.iter().zip(&stmt.value.argv) {
+            match arg.as_str() {
+                "-l" => pids = true,
+                _ => {
+                    error(engine, span.clone(), "Unknown option", String::from("Only '-l' is supported"));
+                    return Ok(())
+                }
+            }
+        }
+
+        engine.notify_jobs();
+
+        let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+            return Ok(())
+        };
+
+        let count = engine.jobs.len();
+
+        for (index, job) in engine.jobs.iter().enumerate() {
+            let mark = match count - index {
+                1 => '+',
+                2 => '-',
+                _ => ' '
+            };
+
+            match pids {
+                true => {
+                    let ids: Vec<String> = job.children.iter().map(|child| child.id().to_string()).collect();
+                    writeln!(stdout, "[{}]{} {} Running {}", job.id, mark, ids.join(" "), job.command)?
+                }
+                false => writeln!(stdout, "[{}]{} Running {}", job.id, mark, job.command)?
+            }
+        }
+
+        stdout.flush()
+    }
+
+    const DISOWN: Builtin = Builtin {
+        run: disown,
+        summary: "Remove background jobs from the job table",
+        usage: "disown [-a] [JOB...]\n\nRemoves each JOB, written as '%1' or '1', from the job table without stopping it, so it's no longer listed by 'jobs' or waited for by 'wait'.\nWithout JOB the latest job is removed, and with '-a' every job is."
+    };
+
+    fn disown(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let mut ids = Vec::new();
+
+        for arg in &stmt.value.argv {
+            let text = span_text(&engine.source, arg);
+
+            if text == "-a" {
+                ids.extend(engine.jobs.iter().map(|job| job.id));
+                continue;
+            }
+
+            // Jobs can be referred to as either '%1' or '1'
+            match text.trim_start_matches('%').parse::<usize>().ok().filter(|id| engine.jobs.iter().any(|job| job.id == *id)) {
+                Some(id) => ids.push(id),
+                None => {
+                    error(engine, arg.clone(), "Unknown job", String::from("There is no background job with this id"));
+                    engine.last_status = 1;
+                    return Ok(())
+                }
+            }
+        }
+
+        if stmt.value.argv.is_empty() {
+            let Some(job) = engine.jobs.last() else {
+                error(engine, stmt.value.program.clone(), "No current job", String::from("There are no background jobs to disown"));
+                engine.last_status = 1;
+                return Ok(())
+            };
+
+            ids.push(job.id);
+        }
+
+        // The processes keep running, they're only reaped so they don't linger once they exit
+        let (removed, kept): (Vec<Job>, Vec<Job>) = std::mem::take(&mut engine.jobs).into_iter().partition(|job| ids.contains(&job.id));
+        engine.jobs = kept;
+        engine.disowned.extend(removed.into_iter().flat_map(|job| job.children));
+
+        Ok(())
+    }
+
+    const KILL: Builtin = Builtin {
+        run: kill,
+        summary: "Send a signal to a job or process",
+        usage: "kill [-SIGNAL] TARGET...\n\nSends SIGNAL, such as '-9' or '-KILL', to each TARGET. Targets are jobs written as '%1' or process ids.\nWithout SIGNAL the target is killed."
+    };
+
+    fn kill(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let mut targets = stmt.value.argv.as_slice();
+        let mut signal = None;
+
+        // An optional leading '-SIGNAL' such as '-9' or '-KILL'
+        if let Some(first) = targets.first().filter(|arg| span_text(&engine.source, arg).starts_with('-')) {
+            match parse_signal(span_text(&engine.source, &(first.start + 1 .. first.end))) {
+                Some(sig) => signal = Some(sig),
+                None => {
+                    error(engine, first.clone(), "Invalid signal", String::from("Expected a signal number or a name such as 'TERM' or 'KILL'"));
+                    return Ok(())
+                }
+            }
+
+            targets = &targets[1..];
+        }
+
+        if targets.is_empty() {
+            error(engine, stmt.value.program.clone(), "Missing target", String::from("Expected a job such as '%1' or a process id"));
+            return Ok(())
+        }
+
+        for target in targets {
+            let text = span_text(&engine.source, target);
+
+            if let Some(id) = text.strip_prefix('%') {
+                let id = id.parse::<usize>().ok();
+
+                let Some(index) = engine.jobs.iter().position(|job| Some(job.id) == id) else {
+                    error(engine, target.clone(), "Unknown job", String::from("There is no background job with this id"));
+                    engine.last_status = 1;
+                    return Ok(())
+                };
+
+                // The job is reaped straight away so it doesn't linger in the job table
+                let mut job = engine.jobs.remove(index);
+
+                for child in &mut job.children {
+                    match signal {
+                        Some(sig) => signal_process(child.id(), sig)?,
+                        None => child.kill()?
+                    }
+                }
+
+                engine.wait_children(job.children)?;
+            } else {
+                let Ok(pid) = text.parse::<u32>() else {
+                    error(engine, target.clone(), "Invalid target", String::from("Expected a job such as '%1' or a process id"));
+                    return Ok(())
+                };
+
+                if let Err(err) = signal_process(pid, signal.unwrap_or(DEFAULT_SIGNAL)) {
+                    error(engine, target.clone(), "Unable to signal process", err.to_string());
+                    engine.last_status = 1;
+                    return Ok(())
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    const DEFAULT_SIGNAL: i32 = libc::SIGTERM;
+
+    #[cfg(target_os = "windows")]
+    const DEFAULT_SIGNAL: i32 = 1;
+
+    #[cfg(target_os = "linux")]
+    fn parse_signal(text: &str) -> Option<i32> {
+        if let Ok(number) = text.parse::<i32>() {
+            return Some(number);
+        }
+
+        match text.trim_start_matches("SIG") {
+            "HUP" => Some(libc::SIGHUP),
+            "INT" => Some(libc::SIGINT),
+            "KILL" => Some(libc::SIGKILL),
+            "TERM" => Some(libc::SIGTERM),
+            "STOP" => Some(libc::SIGSTOP),
+            "CONT" => Some(libc::SIGCONT),
+            _ => None
+        }
+    }
+
+    // Windows has no signals so any signal terminates the process
+    #[cfg(target_os = "windows")]
+    fn parse_signal(text: &str) -> Option<i32> {
+        text.parse::<i32>().ok().or_else(|| ["HUP", "INT", "KILL", "TERM"].contains(&text.trim_start_matches("SIG")).then_some(DEFAULT_SIGNAL))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn signal_process(pid: u32, signal: i32) -> std::io::Result<()> {
+        // Safety: kill has no memory safety requirements, invalid pids are reported through errno
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    fn signal_process(pid: u32, signal: i32) -> std::io::Result<()> {
+        use std::ffi::c_void;
+
+        const PROCESS_TERMINATE: u32 = 0x0001;
+
+        #[link(name = "kernel32")]
+        unsafe extern "system" {
+            fn OpenProcess(access: u32, inherit: i32, pid: u32) -> *mut c_void;
+            fn TerminateProcess(process: *mut c_void, exit_code: u32) -> i32;
+            fn CloseHandle(handle: *mut c_void) -> i32;
+        }
+
+        unsafe {
+            let process = OpenProcess(PROCESS_TERMINATE, 0, pid);
+
+            if process.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            let terminated = TerminateProcess(process, signal as u32);
+            CloseHandle(process);
+
+            if terminated == 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    const SOURCE: Builtin = Builtin {
+        run: source,
+        summary: "Run a file in the current shell",
+        usage: "source FILE [ARG...]\n\nRuns each line of FILE as if it were typed at the prompt. ARGs become the positional parameters."
+    };
+
+    fn source(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some(path_span) = stmt.value.argv.first() else {
+            error(engine, stmt.value.program.clone(), "Missing file", String::from("Expected the path of a file to run"));
+            return Ok(())
+        };
+
+        let path = engine.args[0].clone();
+
+        if Path::new(&path).is_dir() {
+            error(engine, path_span.clone(), "Is a directory", format!("'{}' is a directory, not a file to run", path));
+            engine.last_status = 1;
+            return Ok(())
+        }
+
+        // Arguments after the file replace the positional parameters while it runs
+        let result = if engine.args.len() > 1 {
+            let args = engine.args[1..].to_vec();
+            engine.run_script(&path, args)
+        } else {
+            engine.run_file(&path)
+        };
+
+        if let Err(err) = result {
+            error(engine, path_span.clone(), "Unable to run file", err.to_string());
+            engine.last_status = 1;
+        }
+
+        Ok(())
+    }
+
+    const PUSHD: Builtin = Builtin {
+        run: pushd,
+        summary: "Save the current directory and change to another",
+        usage: "pushd [DIR]\n\nPushes the current directory onto the stack and changes to DIR.\nWithout DIR the current directory swaps with the top of the stack."
+    };
+
+    fn pushd(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let previous = engine.cur_dir.clone();
+
+        let (target, span) = match stmt.value.argv.first() {
+            Some(arg) => (engine.args[0].clone(), arg.clone()),
+
+            // Without an argument the current directory swaps with the top of the stack
+            None => match engine.dir_stack.pop() {
+                Some(top) => (top, stmt.value.program.clone()),
+                None => {
+                    error(engine, stmt.value.program.clone(), "No other directory", String::from("The directory stack is empty"));
+                    engine.last_status = 1;
+                    return Ok(())
+                }
+            }
+        };
+
+        if let Err(err) = engine.change_dir(&target) {
+            error(engine, span, "Unable to change directory", err.to_string());
+            engine.last_status = 1;
+            return Ok(())
+        }
+
+        engine.dir_stack.push(previous);
+        dirs(engine, stmt)
+    }
+
+    const POPD: Builtin = Builtin {
+        run: popd,
+        summary: "Return to the directory on top of the stack",
+        usage: "popd\n\nRemoves the top of the directory stack and changes to it."
+    };
+
+    fn popd(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some(top) = engine.dir_stack.pop() else {
+            error(engine, stmt.value.program.clone(), "No other directory", String::from("The directory stack is empty"));
+            engine.last_status = 1;
+            return Ok(())
+        };
+
+        if let Err(err) = engine.change_dir(&top) {
+            error(engine, stmt.value.program.clone(), "Unable to change directory", format!("{}: {}", top, err));
+            engine.last_status = 1;
+            return Ok(())
+        }
+
+        dirs(engine, stmt)
+    }
+
+    const DIRS: Builtin = Builtin {
+        run: dirs,
+        summary: "Print the directory stack",
+        usage: "dirs\n\nPrints the current directory followed by the saved directories, most recent first."
+    };
+
+    fn dirs(engine: &mut crate::Engine, _: &Spanned<Program>) -> std::io::Result<()> {
+        // The current directory is always the top entry
+        let entries: Vec<&str> = std::iter::once(engine.cur_dir.as_str())
+            .chain(engine.dir_stack.iter().rev().map(|dir| dir.as_str()))
+            .collect();
+
+        println!("{}", entries.join(" "));
+
+        Ok(())
+    }
+
+    const EXPORT: Builtin = Builtin {
+        run: export,
+        summary: "Pass variables on to programs",
+        usage: "export [-p] [NAME[=VALUE]...]\n\nMarks each NAME to be passed to programs, assigning VALUE if given.\nWithout names, or with '-p', the exported variables are printed in a form that can be sourced."
+    };
+
+    fn export(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        // Without names, or with '-p', the exported variables are listed as commands which recreate them
+        if engine.args.iter().all(|arg| arg == "-p") {
+            let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+                return Ok(())
+            };
+
+            let mut names: Vec<&String> = engine.exported.iter().collect();
+            names.sort();
+
+            for name in names {
+                match engine.vars.get(name) {
+                    Some(value) => writeln!(stdout, "export {}={}", name, quote_value(value))?,
+                    None => writeln!(stdout, "export {}", name)?
+                }
+            }
+
+            return stdout.flush()
+        }
+
+        for arg in &stmt.value.argv {
+            let text = span_text(&engine.source, arg);
+
+            // Either 'NAME=value' which also assigns, or just 'NAME'
+            let name = match text.split_once('=') {
+                Some((name, value)) => {
+                    let value = expand::expand_word(engine, value);
+                    engine.vars.insert(name.to_string(), value);
+                    name
+                }
+                None => text
+            };
+
+            if !InputLexer::is_name(name.as_bytes()) {
+                error(engine, arg.clone(), "Invalid variable name", String::from("Names may only contain letters, digits and '_'"));
+                engine.last_status = 1;
+                continue;
+            }
+
+            engine.exported.insert(name.to_string());
+        }
+
+        Ok(())
+    }
+
+    const DECLARE: Builtin = Builtin {
+        run: declare,
+        summary: "Set variables and their attributes",
+        usage: "declare [-i|+i] [NAME[=VALUE]...]\n\nAssigns VALUE to each NAME. With '-i' NAME holds an integer, so every value assigned to it is evaluated\nlike '$((VALUE))', and '+i' removes that again. Without names the variables are printed in a form that can\nbe sourced, only the integers with '-i'."
+    };
+
+    fn declare(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let mut integer = None;
+        let mut names = stmt.value.argv.as_slice();
+
+        // Options come first, as in 'declare -i count=0'
+        while let Some(arg) = names.first() {
+            match span_text(&engine.source, arg) {
+                "-i" => integer = Some(true),
+                "+i" => integer = Some(false),
+                "--" => {
+                    names = &names[1..];
+                    break;
+                }
+
+                option if option.starts_with(['-', '+']) => {
+                    error(engine, arg.clone(), "Unknown option", String::from("Expected '-i' or '+i'"));
+                    return Ok(())
+                }
+
+                _ => break
+            }
+
+            names = &names[1..];
+        }
+
+        if names.is_empty() {
+            let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+                return Ok(())
+            };
+
+            let mut vars: Vec<(&String, &String)> = engine.vars.iter()
+                .filter(|(name, _)| integer != Some(true) || engine.integers.contains(*name))
+                .collect();
+            vars.sort();
+
+            for (name, value) in vars {
+                let flag = if engine.integers.contains(name) { "-i" } else { "--" };
+                writeln!(stdout, "declare {} {}={}", flag, name, quote_value(value))?;
+            }
+
+            return stdout.flush()
+        }
+
+        let source = engine.source.clone();
+
+        for arg in names {
+            let text = span_text(&source, arg);
+            let (name, value) = text.split_once('=').map_or((text, None), |(name, value)| (name, Some(value)));
+
+            if !InputLexer::is_name(name.as_bytes()) {
+                error(engine, arg.clone(), "Invalid variable name", String::from("Names may only contain letters, digits and '_'"));
+                engine.last_status = 1;
+                continue;
+            }
+
+            match integer {
+                Some(true) => engine.integers.insert(name.to_string()),
+                Some(false) => engine.integers.remove(name),
+                None => false
+            };
+
+            // Assigned like 'NAME=value' would be, so the attribute set above already applies
+            if let Some(value) = value {
+                let (name, value) = engine.assignment(name, value);
+
+                if engine.report_expansion_error(&source, arg.clone()) {
+                    continue;
+                }
+
+                engine.vars.insert(name.to_string(), value);
+            }
+        }
+
+        Ok(())
+    }
+
+    const HELP: Builtin = Builtin {
+        run: help,
+        summary: "Describe builtins",
+        usage: "help [NAME]\n\nLists every builtin, or prints the usage of builtin NAME."
+    };
+
+    fn help(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+            return Ok(())
+        };
+
+        let Some(name) = engine.args.first().cloned() else {
+            let mut names: Vec<&&str> = engine.builtins.keys().collect();
+            names.sort();
+
+            let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+
+            for name in names {
+                writeln!(stdout, "{:<width$}  {}", name, engine.builtins[*name].summary)?;
+            }
+
+            writeln!(stdout, "\nRun 'help NAME' for the usage of a builtin")?;
+            return stdout.flush()
+        };
+
+        match engine.builtins.get(name.as_str()) {
+            Some(builtin) => {
+                writeln!(stdout, "{}: {}\n", name, builtin.summary)?;
+                writeln!(stdout, "Usage: {}", builtin.usage)?;
+                stdout.flush()
+            }
+
+            None => {
+                let mut report = Report::build(ReportKind::Error, (engine.source_name.as_str(), 0..0))
+                    .with_config(Config::new().with_index_type(IndexType::Byte))
+                    .with_message("Unknown builtin")
+                    .with_label(
+                        Label::new((engine.source_name.as_str(), stmt.value.argv[0].clone()))
+                            .with_message(format!("'{}' is not a builtin", name))
+                    );
+
+                // Anything further away than a third of the name is unlikely to be a typo
+                let threshold = name.chars().count().div_ceil(3).max(1);
+                let suggestion = engine.builtins.keys()
+                    .map(|candidate| (edit_distance(&name, candidate), candidate))
+                    .filter(|(distance, _)| *distance <= threshold)
+                    .min_by_key(|(distance, _)| *distance);
+
+                report = match suggestion {
+                    Some((_, candidate)) => report.with_note(format!("Did you mean '{}'?", candidate)),
+                    None => report.with_note("Run 'help' to list every builtin")
+                };
+
+                report
+                    .finish()
+                    .eprint((engine.source_name.as_str(), Source::from(&*engine.source)))
+                    .unwrap();
+
+                engine.last_status = 1;
+                Ok(())
+            }
+        }
+    }
+
+    const ENV: Builtin = Builtin {
+        run: env,
+        summary: "Print the environment or run a command with extra variables",
+        usage: "env [-0] [NAME=VALUE...] [--] [COMMAND [ARG...]]\n   or: env --diff\n\nWithout COMMAND prints the variables passed to programs, one per line or separated by NUL with '-0'.\nOtherwise runs COMMAND with each NAME set to VALUE.\n'--diff' shows variables which aren't passed to programs and those which differ from the environment Phoenix started with."
+    };
+
+    // Lists the environment a command would be given, or runs a command with extra variables as in 'env NAME=value cmd'
+    fn env(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        if engine.args.first().is_some_and(|arg| arg == "--diff") {
+            if let Some(extra) = stmt.value.argv.get(1).cloned() {
+                error(engine, extra, "Invalid argument", String::from("'--diff' can't be combined with other arguments"));
+                return Ok(())
+            }
+
+            return env_diff(engine, stmt)
+        }
+
+        let mut null = false;
+        let mut assignments = Vec::new();
+        let mut index = 0;
+
+        while let Some(arg) = engine.args.get(index) {
+            if arg == "-0" {
+                null = true;
+            } else if arg == "--" {
+                // Skipped along with the command's name by 'nested_command'
+                break;
+            } else if let Some((name, value)) = arg.split_once('=').filter(|(name, _)| InputLexer::is_name(name.as_bytes())) {
+                assignments.push((name.to_string(), value.to_string()));
+            } else {
+                break;
+            }
+
+            index += 1;
+        }
+
+        if let Some((inner, args)) = nested_command(engine, stmt, index) {
+            if null {
+                error(engine, stmt.value.argv[index].clone(), "Invalid option", String::from("'-0' only applies when listing the environment"));
+                return Ok(())
+            }
+
+            return env_command(engine, inner, args, assignments)
+        }
+
+        let source = Rc::clone(&engine.source);
+        let mut entries: Vec<(String, String)> = engine.exported.iter()
+            .filter_map(|name| Some((name.clone(), engine.vars.get(name)?.clone())))
+            .collect();
+
+        // Assignments before 'env' and its own arguments are part of the environment it shows
+        let prefixed = stmt.value.env.iter().map(|assignment| {
+            let (name, value) = engine.assignment(span_text(&source, &assignment.name), span_text(&source, &assignment.value));
+            (name.to_string(), value)
+        });
+
+        for (name, value) in prefixed.collect::<Vec<_>>().into_iter().chain(assignments) {
+            entries.retain(|(existing, _)| *existing != name);
+            entries.push((name, value));
+        }
+
+        entries.sort();
+
+        let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+            return Ok(())
+        };
+
+        // With '-0' entries end in NUL, so values containing newlines can be told apart by other tools
+        let terminator = if null { '\0' } else { '\n' };
+
+        for (name, value) in entries {
+            write!(stdout, "{}={}{}", name, value, terminator)?;
+        }
+
+        stdout.flush()
+    }
+
+    // Compares the shell's variables with the environment Phoenix was started with, to explain what programs see
+    // 'local' variables aren't passed on, 'added' and 'changed' ones are exported and 'removed' ones no longer are
+    fn env_diff(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let inherited: HashMap<String, String> = env::vars().collect();
+        let mut names: Vec<&String> = engine.vars.keys().chain(inherited.keys()).collect();
+        names.sort();
+        names.dedup();
+
+        // Values are escaped so ones containing newlines stay on one line
+        let mut lines = Vec::new();
+
+        for name in names {
+            let value = engine.vars.get(name);
+            let exported = engine.exported.contains(name);
+
+            let line = match (value, inherited.get(name)) {
+                (Some(value), None) if exported => format!("added    {}={:?}", name, value),
+                (Some(value), None) => format!("local    {}={:?}", name, value),
+                (Some(value), Some(original)) if exported && value != original => format!("changed  {}={:?}", name, value),
+                (Some(_), Some(_)) if exported => continue,
+                (Some(value), Some(_)) => format!("removed  {}={:?}", name, value),
+                (None, _) => format!("removed  {}", name)
+            };
+
+            lines.push(line);
+        }
+
+        let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+            return Ok(())
+        };
+
+        for line in lines {
+            writeln!(stdout, "{}", line)?;
+        }
+
+        stdout.flush()
+    }
+
+    // Runs the nested command with the assignments exported, restoring the variables afterwards
+    fn env_command(engine: &mut crate::Engine, inner: Spanned<Program>, args: Vec<String>, assignments: Vec<(String, String)>) -> std::io::Result<()> {
+        let mut saved = Vec::new();
+
+        for (name, value) in assignments {
+            let exported = !engine.exported.insert(name.clone());
+            saved.push((name.clone(), engine.vars.insert(name, value), exported));
+        }
+
+        let source = Rc::clone(&engine.source);
+        let result = engine.dispatch(&source, inner, args);
+
+        for (name, value, exported) in saved.into_iter().rev() {
+            match value {
+                Some(value) => engine.vars.insert(name.clone(), value),
+                None => engine.vars.remove(&name)
+            };
+
+            if !exported {
+                engine.exported.remove(&name);
+            }
+        }
+
+        result
+    }
+
+    const ALIAS: Builtin = Builtin {
+        run: alias,
+        summary: "Define or print aliases",
+        usage: "alias [NAME[=VALUE]...]\n\nDefines NAME to be replaced with VALUE when it starts a command, or prints the alias of NAME.\nWithout arguments every alias is printed in a form that can be sourced."
+    };
+
+    fn alias(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+            return Ok(())
+        };
+
+        if stmt.value.argv.is_empty() {
+            let mut names: Vec<&String> = engine.aliases.keys().collect();
+            names.sort();
+
+            for name in names {
+                writeln!(stdout, "alias {}={}", name, quote_value(&engine.aliases[name]))?;
+            }
+
+            return stdout.flush()
+        }
+
+        let source = engine.source.clone();
+
+        for arg in &stmt.value.argv {
+            let text = span_text(&source, arg);
+
+            // Either 'NAME=value' which defines the alias, or just 'NAME' to print it
+            match text.split_once('=') {
+                Some((name, value)) => {
+                    let value = expand::expand_word(engine, value);
+                    engine.aliases.insert(name.to_string(), value);
+                }
+
+                None => match engine.aliases.get(text) {
+                    Some(value) => writeln!(stdout, "alias {}={}", text, quote_value(value))?,
+                    None => {
+                        error(engine, arg.clone(), "Unknown alias", format!("'{}' is not an alias", text));
+                        engine.last_status = 1;
+                    }
+                }
+            }
+        }
+
+        stdout.flush()
+    }
+
+    const ABBR: Builtin = Builtin {
+        run: abbr,
+        summary: "Define words expanded as they're typed",
+        usage: "abbr [NAME [EXPANSION...]]\n   or: abbr -e NAME...\n\nDefines NAME to be replaced with EXPANSION when it's typed as the first word at the prompt and followed by a space or Enter.\nUnlike an alias the replacement is shown in the line, where it can be edited before it runs.\nWith only NAME its expansion is printed, and without arguments every abbreviation is printed in a form that can be sourced.\n'-e' erases each NAME."
+    };
+
+    fn abbr(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let args = engine.args.clone();
+        let span = |index: usize| stmt.value.argv.get(index).unwrap_or(&stmt.value.program).clone();
+
+        match args.first().map(|arg| arg.as_str()) {
+            Some("-e") => {
+                for (index, name) in args.iter().enumerate().skip(1) {
+                    if engine.abbreviations.remove(name).is_none() {
+                        error(engine, span(index), "Unknown abbreviation", format!("'{}' is not an abbreviation", name));
+                        engine.last_status = 1;
+                    }
+                }
+
+                Ok(())
+            }
+
+            Some(option) if option.starts_with('-') => {
+                error(engine, span(0), "Unknown option", String::from("Expected '-e' or the name of an abbreviation"));
+                Ok(())
+            }
+
+            // Only a single word typed at the prompt is ever looked up
+            Some(name) if args.len() > 1 && name.contains(char::is_whitespace) => {
+                error(engine, span(0), "Invalid abbreviation", String::from("An abbreviation must be a single word"));
+                Ok(())
+            }
+
+            Some(name) if args.len() > 1 => {
+                engine.abbreviations.insert(name.to_string(), args[1..].join(" "));
+                Ok(())
+            }
+
+            name => {
+                let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+                    return Ok(())
+                };
+
+                let mut names: Vec<String> = match name {
+                    Some(name) => vec![name.to_string()],
+                    None => engine.abbreviations.keys().cloned().collect()
+                };
+
+                names.sort();
+
+                for name in names {
+                    match engine.abbreviations.get(&name) {
+                        Some(expansion) => writeln!(stdout, "abbr {} {}", name, quote_value(expansion))?,
+                        None => {
+                            error(engine, span(0), "Unknown abbreviation", format!("'{}' is not an abbreviation", name));
+                            engine.last_status = 1;
+                        }
+                    }
+                }
+
+                stdout.flush()
+            }
+        }
+    }
+
+    const COMPLETE: Builtin = Builtin {
+        run: complete,
+        summary: "Define the words completed for a command's arguments",
+        usage: "complete [-W WORDS | -r] [--] [NAME...]\n\nMakes Tab complete the arguments of each NAME from WORDS, which are separated by spaces, as in 'complete -W \"add commit push\" git'.\n'-r' removes what was defined for each NAME, or for every command when no NAME is given.\nWithout options the definitions of each NAME are printed, or all of them in a form that can be sourced."
+    };
+
+    fn complete(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let mut words = None;
+        let mut remove = None;
+        let mut index = 0;
+
+        // Options come first, as in 'complete -W "add commit" git'
+        while let Some(arg) = engine.args.get(index) {
+            let span = stmt.value.argv.get(index).unwrap_or(&stmt.value.program).clone();
+
+            match arg.as_str() {
+                "-W" => {
+                    let Some(list) = engine.args.get(index + 1) else {
+                        error(engine, span, "Missing words", String::from("Expected the words to complete after this"));
+                        return Ok(())
+                    };
+
+                    words = Some((list.split_whitespace().map(String::from).collect::<Vec<_>>(), span));
+                    index += 1;
+                }
+
+                "-r" => remove = Some(span),
+                "--" => {
+                    index += 1;
+                    break;
+                }
+
+                option if option.starts_with('-') => {
+                    error(engine, span, "Unknown option", String::from("Expected '-W' or '-r'"));
+                    return Ok(())
+                }
+
+                _ => break
+            }
+
+            index += 1;
+        }
+
+        let names = engine.args[index.min(engine.args.len())..].to_vec();
+
+        match (words, remove) {
+            (Some(_), Some(span)) => {
+                error(engine, span, "Conflicting options", String::from("'-r' can't be used with '-W'"));
+            }
+
+            (Some((_, span)), None) if names.is_empty() => {
+                error(engine, span, "Missing command name", String::from("Expected the commands to complete these words for"));
+            }
+
+            (Some((words, _)), None) => {
+                for name in names {
+                    engine.completions.insert(name, words.clone());
+                }
+            }
+
+            (None, Some(_)) if names.is_empty() => engine.completions.clear(),
+
+            (None, Some(_)) => {
+                for name in names {
+                    engine.completions.remove(&name);
+                }
+            }
+
+            (None, None) => {
+                let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+                    return Ok(())
+                };
+
+                let mut listed: Vec<&String> = engine.completions.keys().collect();
+
+                if !names.is_empty() {
+                    listed = names.iter().filter(|name| engine.completions.contains_key(*name)).collect();
+                }
+
+                listed.sort();
+
+                for name in listed {
+                    writeln!(stdout, "complete -W {} {}", quote_value(&engine.completions[name].join(" ")), name)?;
+                }
+
+                stdout.flush()?;
+
+                // Naming a command without completions is an error, after printing the ones that exist
+                if let Some(missing) = names.iter().position(|name| !engine.completions.contains_key(name)) {
+                    let span = stmt.value.argv.get(index + missing).unwrap_or(&stmt.value.program).clone();
+                    error(engine, span, "No completions", format!("Nothing is completed for '{}'", names[missing]));
+                    engine.last_status = 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    const BASENAME: Builtin = Builtin {
+        run: basename,
+        summary: "Print the last component of a path",
+        usage: "basename PATH [SUFFIX]\n\nPrints PATH without its directory or trailing slashes, also removing SUFFIX from the end if it matches."
+    };
+
+    fn basename(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some(arg) = engine.args.first().cloned() else {
+            error(engine, stmt.value.program.clone(), "Missing path", String::from("Expected a path after this"));
+            return Ok(())
+        };
+
+        if let Some(extra) = stmt.value.argv.get(2).cloned() {
+            error(engine, extra, "Too many arguments", String::from("Only a path and a suffix are expected"));
+            return Ok(())
+        }
+
+        let path = arg.trim_end_matches(std::path::is_separator);
+
+        let name = match path.rfind(std::path::is_separator) {
+            Some(index) => &path[index + 1 ..],
+            // A path made only of slashes is the root
+            None if path.is_empty() && !arg.is_empty() => &arg[..1],
+            None => path
+        };
+
+        // The suffix is only removed when something would be left
+        let name = match engine.args.get(1) {
+            Some(suffix) if name != suffix => name.strip_suffix(suffix.as_str()).unwrap_or(name),
+            _ => name
+        };
+
+        let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+            return Ok(())
+        };
+
+        writeln!(stdout, "{}", name)?;
+        stdout.flush()
+    }
+
+    const DIRNAME: Builtin = Builtin {
+        run: dirname,
+        summary: "Print the directory part of a path",
+        usage: "dirname PATH\n\nPrints PATH without its last component, or '.' when PATH has no directory."
+    };
+
+    fn dirname(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some(arg) = engine.args.first().cloned() else {
+            error(engine, stmt.value.program.clone(), "Missing path", String::from("Expected a path after this"));
+            return Ok(())
+        };
+
+        if let Some(extra) = stmt.value.argv.get(1).cloned() {
+            error(engine, extra, "Too many arguments", String::from("Only one path is expected"));
+            return Ok(())
+        }
+
+        let path = arg.trim_end_matches(std::path::is_separator);
+
+        let dir = match path.rfind(std::path::is_separator) {
+            // Slashes between the directory and the name belong to neither, unless they're all that's left
+            Some(index) => match path[..index].trim_end_matches(std::path::is_separator) {
+                "" => &arg[..1],
+                dir => dir
+            },
+            None if path.is_empty() && !arg.is_empty() => &arg[..1],
+            None => "."
+        };
+
+        let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+            return Ok(())
+        };
+
+        writeln!(stdout, "{}", dir)?;
+        stdout.flush()
+    }
+
+    const YES: Builtin = Builtin {
+        run: yes,
+        summary: "Print a line over and over",
+        usage: "yes [STRING...]\n\nPrints the STRINGs separated by spaces, or 'y', on every line until the output is closed."
+    };
+
+    // Only stops once writing fails, a reader closing the pipe ends it like any other broken pipe
+    fn yes(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let line = match engine.args.is_empty() {
+            true => String::from("y\n"),
+            false => engine.args.join(" ") + "\n"
+        };
+
+        let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+            return Ok(())
+        };
+
+        // Writing many lines at once rather than one at a time keeps up with fast readers
+        let block = line.repeat((8192 / line.len()).max(1));
+
+        loop {
+            stdout.write_all(block.as_bytes())?;
+        }
+    }
+
+    // Double quotes a value so it reads back unchanged, escaping the characters that stay special inside them
+    // Newlines are kept as they are since a quoted string may span lines
+    fn quote_value(value: &str) -> String {
+        let mut quoted = String::from("\"");
+
+        for c in value.chars() {
+            if ['$', '"', '\\', '`'].contains(&c) {
+                quoted.push('\\');
+            }
+
+            quoted.push(c);
+        }
+
+        quoted.push('"');
+        quoted
+    }
+
+    const UNSET: Builtin = Builtin {
+        run: unset,
+        summary: "Remove variables",
+        usage: "unset NAME...\n\nRemoves each variable NAME from the shell and the environment of programs."
+    };
+
+    fn unset(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        for arg in &stmt.value.argv {
+            let name = span_text(&engine.source, arg);
+
+            if !InputLexer::is_name(name.as_bytes()) {
+                error(engine, arg.clone(), "Invalid variable name", String::from("Names may only contain letters, digits and '_'"));
+                engine.last_status = 1;
+                continue;
+            }
+
+            // Unsetting a variable that doesn't exist is not an error
+            let name = name.to_string();
+            engine.vars.remove(&name);
+            engine.exported.remove(&name);
+            engine.integers.remove(&name);
+        }
+
+        Ok(())
+    }
+
+    const COMMAND: Builtin = Builtin {
+        run: command,
+        summary: "Run a program, or describe how a name would run",
+        usage: "command [-v] [--] NAME [ARG...]\n\nRuns NAME with ARGs. With '-v' prints the builtin name or path that NAME resolves to instead."
+    };
+
+    fn command(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        // '-v' describes how each name would be run instead of running it
+        if engine.args.first().is_some_and(|arg| arg == "-v") {
+            let names = engine.args[1..].to_vec();
+
+            for name in names {
+                if engine.builtins.contains_key(name.as_str()) {
+                    println!("{}", name);
+                } else if let Some(path) = engine.find_executable(&name) {
+                    println!("{}", path.display());
+                } else {
+                    engine.last_status = 1;
+                }
+            }
+
+            return Ok(())
+        }
+
+        let Some((inner, args)) = nested_command(engine, stmt, 0) else {
+            return Ok(())
+        };
+
+        let source = Rc::clone(&engine.source);
+        engine.dispatch(&source, inner, args)
+    }
+
+    // Builds the statement for a command line written as arguments of a builtin, such as 'command ls'
+    // The argument at 'index' becomes the program, keeping the redirects of the original statement
+    // A '--' there ends the builtin's own options, so the command and its arguments are passed on as written
+    fn nested_command(engine: &crate::Engine, stmt: &Spanned<Program>, index: usize) -> Option<(Spanned<Program>, Vec<String>)> {
+        let index = index + usize::from(engine.args.get(index).is_some_and(|arg| arg == "--"));
+        let program = stmt.value.argv.get(index)?;
+
+        let mut inner = stmt.clone();
+        inner.value.program = program.clone();
+        inner.value.argv = stmt.value.argv[index + 1 ..].to_vec();
+
+        Some((inner, engine.args[index + 1 ..].to_vec()))
+    }
+
+    const TRUE: Builtin = Builtin {
+        run: r#true,
+        summary: "Succeed",
+        usage: "true\n\nDoes nothing and exits with a status of 0."
+    };
+
+    // Builtins start with a status of 0, so 'true' has nothing left to do
+    fn r#true(_: &mut crate::Engine, _: &Spanned<Program>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    const FALSE: Builtin = Builtin {
+        run: r#false,
+        summary: "Fail",
+        usage: "false\n\nDoes nothing and exits with a status of 1."
+    };
+
+    fn r#false(engine: &mut crate::Engine, _: &Spanned<Program>) -> std::io::Result<()> {
+        engine.last_status = 1;
+        Ok(())
+    }
+
+    const TEST: Builtin = Builtin {
+        run: test,
+        summary: "Evaluate a condition",
+        usage: "test EXPRESSION\n\nExits with 0 if EXPRESSION is true and 1 otherwise.\nSupports '-e', '-f', '-d', '-r', '-w', '-x', '-n' and '-z' checks, string comparison with '=' and '!='\nand number comparison with '-eq', '-ne', '-lt', '-le', '-gt' and '-ge'. '!' negates an expression."
+    };
+
+    fn test(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let mut args = engine.args.clone();
+
+        // '[' is the same command but must be closed with ']'
+        if stmt.value.program_str(&engine.source) == "[" {
+            if args.last().is_none_or(|arg| arg != "]") {
+                let end = stmt.span.end;
+                error(engine, end .. end, "Missing ']'", String::from("Expected ']' to close the test here"));
+                return Ok(())
+            }
+
+            args.pop();
+        }
+
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+        match evaluate_test(&args) {
+            Ok(result) => engine.last_status = if result { 0 } else { 1 },
+            Err((index, message)) => {
+                let span = stmt.value.argv.get(index).cloned().unwrap_or(stmt.value.program.clone());
+                error(engine, span, "Invalid test", message);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Evaluates the arguments of 'test', an error holds the index of the offending argument
+    fn evaluate_test(args: &[&str]) -> Result<bool, (usize, String)> {
+        match args {
+            [] => Ok(false),
+            ["!", rest @ ..] => evaluate_test(rest)
+                .map(|result| !result)
+                .map_err(|(index, message)| (index + 1, message)),
+            [string] => Ok(!string.is_empty()),
+            [op, operand] => match *op {
+                "-z" => Ok(operand.is_empty()),
+                "-n" => Ok(!operand.is_empty()),
+                "-e" | "-f" | "-d" | "-r" | "-w" | "-x" => Ok(test_file(op, Path::new(&native_separators(operand)))),
+                _ => Err((0, format!("'{}' is not a unary operator", op)))
+            },
+            [left, op, right] => match *op {
+                "=" | "==" => Ok(left == right),
+                "!=" => Ok(left != right),
+                "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                    let left = left.parse::<i64>().map_err(|_| (0, format!("'{}' is not an integer", left)))?;
+                    let right = right.parse::<i64>().map_err(|_| (2, format!("'{}' is not an integer", right)))?;
+
+                    Ok(match *op {
+                        "-eq" => left == right,
+                        "-ne" => left != right,
+                        "-lt" => left < right,
+                        "-le" => left <= right,
+                        "-gt" => left > right,
+                        _ => left >= right
+                    })
+                }
+                _ => Err((1, format!("'{}' is not a binary operator", op)))
+            },
+            _ => Err((3, String::from("Too many arguments")))
+        }
+    }
+
+    fn test_file(op: &str, path: &Path) -> bool {
+        match op {
+            "-e" => path.exists(),
+            "-f" => path.is_file(),
+            "-d" => path.is_dir(),
+            _ => has_access(op, path)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn has_access(op: &str, path: &Path) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+
+        let mode = match op {
+            "-r" => libc::R_OK,
+            "-w" => libc::W_OK,
+            _ => libc::X_OK
+        };
+
+        let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+            return false
+        };
+
+        // Safety: 'path' is a valid nul-terminated string for the duration of the call
+        unsafe { libc::access(path.as_ptr(), mode) == 0 }
+    }
+
+    #[cfg(target_os = "windows")]
+    fn has_access(op: &str, path: &Path) -> bool {
+        let Ok(metadata) = path.metadata() else {
+            return false
+        };
+
+        // Windows has no permission bits, so writable means not read-only and executable means a PATHEXT extension
+        match op {
+            "-r" => true,
+            "-w" => !metadata.permissions().readonly(),
+            _ => metadata.is_file() && path.extension().is_some_and(|ext| {
+                Engine::executable_extensions().contains(&ext.to_string_lossy().to_ascii_lowercase())
+            })
+        }
+    }
+
+    const PRINTF: Builtin = Builtin {
+        run: printf,
+        summary: "Print formatted text",
+        usage: "printf FORMAT [ARG...]\n\nPrints ARGs following FORMAT, which is reused until every ARG has been used.\nSupports %s, %c, %d, %i, %x, %X, %o and %% with '-' and '0' flags and a width, and backslash escapes."
+    };
+
+    fn printf(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some(format) = engine.args.first().cloned() else {
+            error(engine, stmt.value.program.clone(), "Missing format", String::from("Expected a format string"));
+            return Ok(())
+        };
+
+        let args = engine.args[1..].to_vec();
+        let mut output = String::new();
+        let mut next = 0;
+
+        // The format is reused until every argument has been consumed
+        loop {
+            let start = next;
+
+            if let Err((index, message)) = format_printf(&format, &args, &mut next, &mut output) {
+                error(engine, stmt.value.argv[index].clone(), "Invalid format", message);
+                engine.last_status = 1;
+                return Ok(())
+            }
+
+            if next >= args.len() || next == start {
+                break;
+            }
+        }
+
+        let Some(mut stdout) = engine.builtin_stdout(&stmt.value)? else {
+            return Ok(())
+        };
+
+        stdout.write_all(output.as_bytes())?;
+        stdout.flush()
+    }
+
+    // Formats the arguments from 'next' onwards once, an error holds the index of the offending argument in argv
+    fn format_printf(format: &str, args: &[String], next: &mut usize, output: &mut String) -> Result<(), (usize, String)> {
+        let mut chars = format.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n') => output.push('\n'),
+                    Some('t') => output.push('\t'),
+                    Some('r') => output.push('\r'),
+                    Some('a') => output.push('\x07'),
+                    Some('b') => output.push('\x08'),
+                    Some('v') => output.push('\x0b'),
+                    Some('\\') => output.push('\\'),
+                    Some('0') => output.push('\0'),
+                    Some(other) => {
+                        output.push('\\');
+                        output.push(other);
+                    }
+                    None => output.push('\\')
+                }
+
+                '%' if chars.next_if_eq(&'%').is_some() => output.push('%'),
+
+                '%' => {
+                    let left = chars.next_if_eq(&'-').is_some();
+                    let zero = chars.next_if_eq(&'0').is_some();
+                    let mut width = 0;
+
+                    while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                        width = width * 10 + digit.to_digit(10).unwrap() as usize;
+                    }
+
+                    // Missing arguments are treated as empty strings or zero
+                    let index = *next;
+                    let arg = args.get(index).map(|arg| arg.as_str()).unwrap_or("");
+                    *next += 1;
+
+                    let number = || -> Result<i64, (usize, String)> {
+                        if arg.is_empty() {
+                            return Ok(0)
+                        }
+
+                        arg.trim_start_matches('+').parse::<i64>().map_err(|_| (index + 1, format!("'{}' is not a number", arg)))
+                    };
+
+                    let text = match chars.next() {
+                        Some('s') => arg.to_string(),
+                        Some('c') => arg.chars().next().map(String::from).unwrap_or_default(),
+                        Some('d' | 'i') => number()?.to_string(),
+                        Some('x') => format!("{:x}", number()?),
+                        Some('X') => format!("{:X}", number()?),
+                        Some('o') => format!("{:o}", number()?),
+                        Some(other) => return Err((0, format!("'%{}' is not a supported conversion", other))),
+                        None => return Err((0, String::from("Expected a conversion character after '%'")))
+                    };
+
+                    let padding = width.saturating_sub(text.chars().count());
+
+                    if left {
+                        output.push_str(&text);
+                        output.push_str(&" ".repeat(padding));
+                    } else if zero && !text.starts_with('-') {
+                        output.push_str(&"0".repeat(padding));
+                        output.push_str(&text);
+                    } else {
+                        output.push_str(&" ".repeat(padding));
+                        output.push_str(&text);
+                    }
+                }
+
+                c => output.push(c)
+            }
+        }
+
+        Ok(())
+    }
+
+    const REPEAT: Builtin = Builtin {
+        run: repeat,
+        summary: "Run a command a number of times",
+        usage: "repeat COUNT [--] COMMAND [ARG...]\n\nRuns COMMAND COUNT times. The status is that of the last run."
+    };
+
+    fn repeat(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some(count_span) = stmt.value.argv.first().cloned() else {
+            error(engine, stmt.value.program.clone(), "Missing count", String::from("Expected the number of times to run a command"));
+            return Ok(())
+        };
+
+        let Ok(count) = engine.args[0].parse::<usize>() else {
+            error(engine, count_span, "Invalid count", String::from("Expected a whole number"));
+            return Ok(())
+        };
+
+        let Some((inner, args)) = nested_command(engine, stmt, 1) else {
+            error(engine, count_span, "Missing command", String::from("Expected a command to repeat after this"));
+            return Ok(())
+        };
+
+        let source = Rc::clone(&engine.source);
+
+        for _ in 0 .. count {
+            engine.dispatch(&source, inner.clone(), args.clone())?;
+
+            if engine.options.errexit && engine.last_status != 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    const TIME: Builtin = Builtin {
+        run: time,
+        summary: "Measure how long a command takes",
+        usage: "time [--] COMMAND [ARG...]\n\nRuns COMMAND and prints the real, user and system time it took to stderr."
+    };
+
+    fn time(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some((inner, args)) = nested_command(engine, stmt, 0) else {
+            error(engine, stmt.value.program.clone(), "Missing command", String::from("Expected a command to time"));
+            return Ok(())
+        };
+
+        let source = Rc::clone(&engine.source);
+        let before = child_cpu_time();
+        let started = std::time::Instant::now();
+
+        // The command's own status is kept as the status of 'time'
+        engine.dispatch(&source, inner, args)?;
+
+        let real = started.elapsed();
+        eprintln!("\nreal\t{}", format_duration(real));
+
+        if let (Some(before), Some(after)) = (before, child_cpu_time()) {
+            eprintln!("user\t{}", format_duration(after.0.saturating_sub(before.0)));
+            eprintln!("sys\t{}", format_duration(after.1.saturating_sub(before.1)));
+        }
+
+        Ok(())
+    }
+
+    const TIMEOUT: Builtin = Builtin {
+        run: timeout,
+        summary: "Run a program with a time limit",
+        usage: "timeout SECONDS [--] PROGRAM [ARG...]\n\nRuns PROGRAM and kills it if it's still running after SECONDS, which may be fractional such as '0.5'.\nThe status is 124 when the limit was reached, otherwise the status of PROGRAM.\nOn Linux the processes PROGRAM started are killed along with it."
+    };
+
+    fn timeout(engine: &mut crate::Engine, stmt: &Spanned<Program>) -> std::io::Result<()> {
+        let Some(limit_span) = stmt.value.argv.first().cloned() else {
+            error(engine, stmt.value.program.clone(), "Missing time limit", String::from("Expected the number of seconds to allow"));
+            return Ok(())
+        };
+
+        let Some(limit) = engine.args[0].parse::<f64>().ok().and_then(|secs| std::time::Duration::try_from_secs_f64(secs).ok()) else {
+            error(engine, limit_span, "Invalid time limit", String::from("Expected a number of seconds such as '5' or '0.5'"));
+            return Ok(())
+        };
+
+        let Some((inner, args)) = nested_command(engine, stmt, 1) else {
+            error(engine, limit_span, "Missing command", String::from("Expected a program to run after this"));
+            return Ok(())
+        };
+
+        let source = Rc::clone(&engine.source);
+
+        // Builtins run inside the shell where they can't be stopped, so only programs are looked for
+        let Some(executable) = engine.find_executable(inner.value.program_str(&source)) else {
+            engine.report_unknown_command(&source, inner.value.program);
+            engine.last_status = 127;
+            return Ok(())
+        };
+
+        let Some((mut cmd, input)) = engine.prepare_command(&source, executable, args, &inner.value)? else {
+            return Ok(())
+        };
+
+        own_process_group(&mut cmd);
+
+        let mut child = cmd.spawn()?;
+        super::feed_stdin(&mut child, input);
+
+        let deadline = std::time::Instant::now() + limit;
+
+        // Polling keeps the shell single threaded, and a short sleep is well within the precision anyone asks for
+        loop {
+            if let Some(status) = child.try_wait()? {
+                engine.last_status = super::exit_code(status);
+                return Ok(())
+            }
+
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+
+            if remaining.is_zero() {
+                break;
+            }
+
+            std::thread::sleep(remaining.min(std::time::Duration::from_millis(10)));
+        }
+
+        kill_process_group(&mut child)?;
+        child.wait()?;
+
+        engine.last_status = TIMEOUT_STATUS;
+        Ok(())
+    }
+
+    // The status of a command stopped for running too long, as used by GNU timeout
+    const TIMEOUT_STATUS: i32 = 124;
+
+    // A group of its own lets everything the program starts be killed together
+    #[cfg(target_os = "linux")]
+    fn own_process_group(cmd: &mut std::process::Command) {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    #[cfg(target_os = "windows")]
+    fn own_process_group(_: &mut std::process::Command) {}
+
+    #[cfg(target_os = "linux")]
+    fn kill_process_group(child: &mut std::process::Child) -> std::io::Result<()> {
+        // Safety: the child leads its own group, so the negative pid names only processes it started
+        if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+
+        Ok(())
+    }
+
+    // Windows has no process groups to signal, so only the program itself is killed
+    #[cfg(target_os = "windows")]
+    fn kill_process_group(child: &mut std::process::Child) -> std::io::Result<()> {
+        child.kill()
+    }
+
+    // Formats a duration like other shells' 'time', such as '0m1.250s'
+    fn format_duration(duration: std::time::Duration) -> String {
+        let secs = duration.as_secs_f64();
+        format!("{}m{:.3}s", (secs / 60.0).floor(), secs % 60.0)
+    }
+
+    // User and system time used by every child waited for so far
+    #[cfg(target_os = "linux")]
+    fn child_cpu_time() -> Option<(std::time::Duration, std::time::Duration)> {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+        // Safety: 'usage' is a valid rusage for getrusage to fill in
+        if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+            return None
+        }
+
+        let duration = |time: libc::timeval| {
+            std::time::Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+        };
+
+        Some((duration(usage.ru_utime), duration(usage.ru_stime)))
+    }
+
+    // Windows has no total for child processes, so only the real time is shown
+    #[cfg(target_os = "windows")]
+    fn child_cpu_time() -> Option<(std::time::Duration, std::time::Duration)> {
+        None
+    }
+}
@@ -0,0 +1,244 @@
+use crate::arithmetic;
+use crate::ast::Program;
+use crate::engine::Engine;
+use crate::input_lexer::ESCAPE;
+use std::borrow::Cow;
+
+// Characters that split unquoted substitutions into separate arguments when 'IFS' is unset
+pub const DEFAULT_IFS: &str = " \t\n";
+
+// Produces the final arguments passed to a program, applying the expansions of each word in the order other shells do:
+// tilde, then parameters and arithmetic, then splitting unquoted substitutions on 'IFS', then removing quotes
+// Brace expansion belongs before tilde and pathname expansion after splitting, once they're supported
+pub fn expand_argv(engine: &Engine, source: &str, program: &Program) -> Vec<String> {
+    let ifs = engine.lookup_var("IFS").unwrap_or_else(|| String::from(DEFAULT_IFS));
+
+    program.argv_strs(source)
+        .into_iter()
+        .flat_map(|arg| {
+            if arg.starts_with(['\'', '"']) {
+                vec![expand_word(engine, arg)]
+            } else {
+                expand_fields(engine, &expand_tilde(engine, arg), false, Some(&ifs))
+            }
+        })
+        .collect()
+}
+
+// Expands variables in a word and removes its quotes, text in single quotes is left untouched
+pub fn expand_word(engine: &Engine, word: &str) -> String {
+    expand_vars(engine, &expand_tilde(engine, word), false)
+}
+
+// Replaces a leading '~' with the home directory, which applies to every unquoted word
+// so arguments, redirect targets and 'cd' all agree
+pub fn expand_tilde<'a>(engine: &Engine, word: &'a str) -> Cow<'a, str> {
+    match word.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', std::path::MAIN_SEPARATOR]) => {
+            let home = engine.lookup_var("HOME").unwrap_or_else(Engine::get_base_dir);
+            Cow::Owned(home + rest)
+        }
+
+        _ => Cow::Borrowed(word)
+    }
+}
+
+// Substitutes '$NAME', '${NAME}' and '$?', resolving backslash escapes inside double quotes
+pub fn expand_vars(engine: &Engine, text: &str, in_quotes: bool) -> String {
+    expand_fields(engine, text, in_quotes, None).concat()
+}
+
+// Expands a word into the fields it produces, substituted values are split on the characters in 'ifs'
+fn expand_fields(engine: &Engine, text: &str, in_quotes: bool, ifs: Option<&str>) -> Vec<String> {
+    let mut result = Fields::new(ifs);
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            // Inside double quotes a backslash only escapes characters that are otherwise special
+            '\\' if in_quotes => match chars.next_if(|next| ['$', '"', '\\', '`'].contains(next)) {
+                Some(escaped) => result.push_literal(escaped),
+                None => result.push_literal('\\')
+            }
+
+            // Quotes in a word such as 'A="x y"z' are removed, the text inside them is never split
+            '\'' if !in_quotes => {
+                let text: String = chars.by_ref().take_while(|c| *c != '\'').collect();
+                result.push_quoted(&text);
+            }
+
+            '"' if !in_quotes => {
+                let text = double_quoted_text(&mut chars);
+                result.push_quoted(&expand_vars(engine, &text, true));
+            }
+
+            // Outside quotes an escaped character is kept literally and an escaped newline joins the lines
+            c if !in_quotes && Some(c) == ESCAPE => match chars.next() {
+                Some('\n') | None => (),
+                Some(escaped) => result.push_literal(escaped)
+            }
+
+            '$' if chars.clone().take(2).eq(['(', '(']) => {
+                chars.nth(1);
+                let value = arithmetic(engine, &arithmetic_text(&mut chars)).unwrap_or_default();
+                result.push_value(&value.to_string());
+            }
+
+            '$' => match chars.peek() {
+                Some('?') => {
+                    chars.next();
+                    result.push_value(&engine.last_status.to_string());
+                }
+
+                Some('#') => {
+                    chars.next();
+                    result.push_value(&engine.positional().len().saturating_sub(1).to_string());
+                }
+
+                Some('@') => {
+                    chars.next();
+                    result.push_value(&engine.positional().iter().skip(1).cloned().collect::<Vec<_>>().join(" "));
+                }
+
+                // Only a single digit is read, '${10}' is needed for later parameters
+                Some(c) if c.is_ascii_digit() => {
+                    let name = chars.next().unwrap().to_string();
+                    result.push_value(&engine.lookup_var(&name).unwrap_or_default());
+                }
+
+                Some('{') => {
+                    chars.next();
+                    let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                    result.push_value(&engine.lookup_var(&name).unwrap_or_default());
+                }
+
+                Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+                    let mut name = String::new();
+
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                        name.push(c);
+                    }
+
+                    result.push_value(&engine.lookup_var(&name).unwrap_or_default());
+                }
+
+                // A lone '$' is kept literally
+                _ => result.push_literal('$')
+            }
+
+            c => result.push_literal(c)
+        }
+    }
+
+    result.finish()
+}
+
+// Evaluates an arithmetic expression after substituting its variables, or None after recording why it failed
+pub fn arithmetic(engine: &Engine, expr: &str) -> Option<i64> {
+    let expanded = expand_vars(engine, expr, true);
+
+    match arithmetic::evaluate(&expanded, &|name| engine.lookup_var(name)) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            engine.expansion_failed(format!("{} in '{}'", err, expr.trim()));
+            None
+        }
+    }
+}
+
+// Takes the expression of an arithmetic expansion from after its '$((' up to the matching '))'
+// Quoted text the lexer didn't check may be missing the end, in which case the rest is the expression
+fn arithmetic_text(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut text = String::new();
+    let mut depth = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            ')' if depth == 0 && chars.next_if_eq(&')').is_some() => break,
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => ()
+        }
+
+        text.push(c);
+    }
+
+    text
+}
+
+// Takes the text of a double quoted part of a word from after its opening quote up to the closing one
+// Escaped characters are kept with their backslash, which is resolved when the text is expanded
+fn double_quoted_text(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut text = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => {
+                text.push(c);
+                text.extend(chars.next());
+            }
+            c => text.push(c)
+        }
+    }
+
+    text
+}
+
+// Collects the fields a word expands to, splitting substituted values like other shells
+// Whitespace in 'IFS' separates fields in runs, other characters in it end a field each time they appear
+struct Fields<'a> {
+    ifs: Option<&'a str>,
+    fields: Vec<String>,
+    current: Option<String>, // Field being built, None between fields
+    after_space: bool // Whether whitespace in 'IFS' just ended a field, which a following delimiter joins
+}
+
+impl<'a> Fields<'a> {
+    fn new(ifs: Option<&'a str>) -> Self {
+        Self { ifs, fields: Vec::new(), current: None, after_space: false }
+    }
+
+    // Text written in the word itself is never split
+    fn push_literal(&mut self, c: char) {
+        self.current.get_or_insert_default().push(c);
+        self.after_space = false;
+    }
+
+    // Quoted text is kept whole, and even when empty it makes a field
+    fn push_quoted(&mut self, text: &str) {
+        self.current.get_or_insert_default().push_str(text);
+        self.after_space = false;
+    }
+
+    fn push_value(&mut self, value: &str) {
+        let Some(ifs) = self.ifs else {
+            self.current.get_or_insert_default().push_str(value);
+            return
+        };
+
+        for c in value.chars() {
+            if !ifs.contains(c) {
+                self.push_literal(c);
+            } else if c.is_whitespace() {
+                if let Some(field) = self.current.take() {
+                    self.fields.push(field);
+                    self.after_space = true;
+                }
+            } else if !std::mem::take(&mut self.after_space) {
+                self.fields.push(self.current.take().unwrap_or_default());
+            }
+        }
+    }
+
+    // A word whose only content was an empty substitution produces no fields when split
+    fn finish(mut self) -> Vec<String> {
+        match self.current {
+            Some(field) => self.fields.push(field),
+            None if self.ifs.is_none() => self.fields.push(String::new()),
+            None => ()
+        }
+
+        self.fields
+    }
+}
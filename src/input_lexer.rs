@@ -1,40 +1,30 @@
 use std::ops::Range;
-use ariadne::{Label, Report, ReportKind, Source};
+use ariadne::{Config, IndexType, Label, Report, ReportKind, Source};
+use crate::log::LogFlags;
 
-// Cannot display fancy errors here if we don't restrict which type is available here
+// Separators accepted inside paths, with the platform's native separator first for error messages
+// Windows APIs accept either slash so both are allowed there
 #[cfg(target_os = "windows")]
-const SLASH: char = '\\';
+const SLASHES: [char; 2] = ['\\', '/'];
 #[cfg(target_os = "linux")]
-const SLASH: char = '/';
+const SLASHES: [char; 1] = ['/'];
 
 // This as used as char exceptions for classifying identifiers
 // Unfortunately OS-dependant since windows uses '/' and '?' inside program arguments
 #[cfg(target_os = "windows")]
-const IDENT_EXCEPT: [char; 4] = ['/', '?', '-', '.'];
+const IDENT_EXCEPT: [char; 16] = ['/', '?', '-', '.', '_', '+', '%', '$', '{', '}', '#', '@', '!', '[', ']', '~'];
 #[cfg(target_os = "linux")]
-const IDENT_EXCEPT: [char; 2] = ['-', '.'];
-
-// Macro assumes that 'this' is in scope of 'InputLexer'
-macro_rules! expect_char {
-    ( $this:expr, $expected:expr, $span:expr $(, $hint:expr)? ) => {{
-        if $this.cur_char != $expected {
-            Report::build(ReportKind::Error, ("stdin", 0..0))
-                .with_message("Invalid expression")
-                .with_label(
-                    Label::new(("stdin", $span))
-                        .with_message(format!("Expected '{}' here", $expected))
-                )
-                $(.with_note($hint))?
-                .finish()
-                .print(("stdin", Source::from(String::from_utf8($this.source.clone().into()).unwrap())))
-                .unwrap();
+const IDENT_EXCEPT: [char; 15] = ['-', '.', '_', '+', '%', '$', '{', '}', '?', '#', '@', '!', '[', ']', '~'];
 
-            return None
-        }
+// Makes the following character part of an unquoted word, as in 'a\ b' or '\>'
+// Windows uses '\' as a path separator so it has no escape character
+#[cfg(target_os = "windows")]
+pub const ESCAPE: Option<char> = None;
+#[cfg(target_os = "linux")]
+pub const ESCAPE: Option<char> = Some('\\');
 
-        $this.next_char();
-    }}
-}
+// Characters which always start a new operator token and therefore end an unquoted word
+const OPERATOR_CHARS: [char; 7] = ['|', '<', '>', '&', '(', ')', ';'];
 
 // Creates a default token of $var type with no text or span
 #[macro_export]
@@ -44,202 +34,372 @@ macro_rules! default_token {
     }
 }
 
-pub struct InputLexer {
-    source: Vec<u8>,
+// Lexing is a single pass over the borrowed source, so an N-byte line takes O(N) time with no copies of it
+// Only error reports build an owned copy of the source, once for the error being printed
+pub struct InputLexer<'a> {
+    name: &'a str, // Identifies the source in error reports such as 'stdin' or a file path
+    source: &'a str,
     cur_char: char,
     peek_char: char,
     index: usize,
+    failed: bool, // Set when an error is reported while lexing the current token
+    incomplete: bool, // Set when the error is that the input ended too early
+    quiet: bool, // Errors aren't printed when only checking for incomplete input
+    heredoc_pending: bool, // Set after '<<' until its delimiter has been lexed
+    heredoc_end: Option<usize>, // Where lexing resumes at the end of the line, past any here-document bodies
+    queued: Option<Token>, // A here-document body waiting to be returned after its delimiter
+    open_groups: usize, // Groups started with '(' or '{' which haven't been closed yet
+    command_start: bool // Whether the next word is the first of a statement, where '{' and '}' start and end groups
 }
 
-impl InputLexer {
-    pub fn new(mut source: Vec<u8>) -> Self {
-        #[cfg(target_os = "windows")]
-        for _ in 0..2 { source.pop().unwrap(); }
+impl<'a> InputLexer<'a> {
+    pub fn new(name: &'a str, source: &'a str) -> Self {
+        // Strip the line ending, which may be missing for lines read from a file
+        let source = source.trim_end_matches(['\n', '\r']);
 
-        let cur_char = *source.get(0).unwrap_or(&0) as char;
-        let peek_char = *source.get(1).unwrap_or(&0) as char;
-
-        Self {
+        let mut lexer = Self {
+            name,
             source,
-            cur_char,
-            peek_char,
-            index: 0
+            cur_char: '\x03',
+            peek_char: '\x03',
+            index: 0,
+            failed: false,
+            incomplete: false,
+            quiet: false,
+            heredoc_pending: false,
+            heredoc_end: None,
+            queued: None,
+            open_groups: 0,
+            command_start: true
+        };
+
+        lexer.seek(0);
+        lexer
+    }
+
+    // Prints an error unless only checking whether the input is complete, either way the tokens can't be used
+    fn emit(&mut self, report: Report<'a, (&'a str, Range<usize>)>) {
+        if !self.quiet {
+            report.print((self.name, Source::from(self.source))).unwrap();
         }
+
+        self.failed = true;
+    }
+
+    // Lexes without printing errors, for looking over input which is lexed again when it runs
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+
+    // Whether the input stops partway through a construct which continues on following lines,
+    // such as a quoted string, a here-document, a group or a line ending in '|', '&&' or '||'
+    // Errors aren't reported here, the complete input is lexed again when it runs
+    pub fn is_incomplete(source: &str) -> bool {
+        let mut lexer = InputLexer::new("", source).quiet();
+        let last = lexer.by_ref().filter(|token| token.typ != TokenType::Whitespace).last();
+
+        lexer.incomplete
+            || lexer.open_groups > 0
+            || last.is_some_and(|token| matches!(token.typ, TokenType::Pipe | TokenType::LogicalAnd | TokenType::LogicalOr))
     }
 
+    // Indices are byte offsets, so moving past a character skips all of its UTF-8 bytes
+    // and every span starts and ends on a character boundary
     fn next_char(&mut self) {
-        self.index += 1;
-        self.cur_char = *self.source.get(self.index).unwrap_or(&0x03u8) as char;
-        self.peek_char = *self.source.get(self.index + 1).unwrap_or(&0x03u8) as char;
+        let len = if self.index < self.source.len() { self.cur_char.len_utf8() } else { 1 };
+        self.seek(self.index + len);
     }
 
-    pub fn next_token(&mut self) -> Option<Token> {
-        match self.cur_char {
-            // Identifier
-            // Accepts IDENT_EXCEPT characters for purposes of file extensions and argv
-            c if (c.is_alphabetic() && self.peek_char != ':') || IDENT_EXCEPT.contains(&c) => {
-                let start = self.index;
+    // Counts '{' and '}' used as the first word of a statement so a brace group can continue on following lines
+    fn track_braces(&mut self, token: &Token) {
+        let text = &self.source[token.start .. token.end];
+
+        self.command_start = match token.typ {
+            TokenType::Identifier if self.command_start && text == "{" => {
+                self.open_groups += 1;
+                true
+            }
+
+            TokenType::Identifier if self.command_start && text == "}" => {
+                self.open_groups = self.open_groups.saturating_sub(1);
+                false
+            }
+
+            TokenType::Whitespace | TokenType::Assignment => self.command_start,
+
+            typ => matches!(
+                typ,
+                TokenType::Newline | TokenType::Semicolon | TokenType::LParen | TokenType::Pipe
+                    | TokenType::And | TokenType::LogicalAnd | TokenType::LogicalOr
+            )
+        };
+    }
+
+    // Moves past a character of an unquoted word, taking the character after an escape along with it
+    // An arithmetic expansion is taken whole, since its spaces and parentheses belong to the word
+    fn next_word_char(&mut self) {
+        if self.source[self.index ..].starts_with("$((") {
+            return self.skip_arithmetic();
+        }
 
-                while self.cur_char.is_alphanumeric() || IDENT_EXCEPT.contains(&self.cur_char) {
+        if Some(self.cur_char) == ESCAPE && self.index + 1 < self.source.len() {
+            self.next_char();
+        }
+
+        self.next_char();
+    }
+
+    // Moves past '$((' up to the '))' which closes it, counting the parentheses in between
+    fn skip_arithmetic(&mut self) {
+        let start = self.index;
+        let mut depth = 0;
+
+        self.seek(self.index + 3);
+
+        loop {
+            match self.cur_char {
+                ')' if depth == 0 && self.peek_char == ')' => {
                     self.next_char();
+                    self.next_char();
+                    return;
                 }
 
-                let end = self.index;
+                '(' => depth += 1,
+                ')' => depth -= 1,
 
-                // Check if there is a file extension
-                if str::from_utf8(&self.source[start .. end]).unwrap().contains('.') {
-                    return Some(Token::new(
-                        TokenType::Path,
-                        start .. end
-                    ))
+                '\x03' => {
+                    self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                        .with_config(Config::new().with_index_type(IndexType::Byte))
+                        .with_message("Unterminated arithmetic expansion")
+                        .with_label(
+                            Label::new((self.name, start .. self.source.len()))
+                                .with_message("Expected '))' to end the expression")
+                        )
+                        .finish());
+
+                    return;
                 }
 
-                return Some(Token::new(
-                    TokenType::Identifier,
-                    start .. end
-                ));
+                _ => ()
             }
 
-            // Number
-            c if c.is_numeric() => {
-                let start = self.index;
+            self.next_char();
+        }
+    }
 
-                while self.cur_char.is_numeric() {
-                    self.next_char();
-                }
+    // Moves to an arbitrary position, such as past here-document bodies
+    fn seek(&mut self, index: usize) {
+        let mut chars = self.source.get(index ..).unwrap_or_default().chars();
 
-                let end = self.index;
+        self.index = index;
+        self.cur_char = chars.next().unwrap_or('\x03');
+        self.peek_char = chars.next().unwrap_or('\x03');
+    }
 
-                return Some(Token::new(
-                    TokenType::Number,
-                    start .. end
-                ));
+    pub fn next_token(&mut self) -> Option<Token> {
+        if let Some(token) = self.queued.take() {
+            return Some(token)
+        }
+
+        let start = self.index;
+        self.failed = false;
+
+        let mut token = self.lex_token();
+
+        // The word after '<<' is the delimiter, and its body follows it as a token of its own
+        if let Some(delimiter) = token.filter(|token| self.heredoc_pending && matches!(
+            token.typ,
+            TokenType::Identifier | TokenType::Path | TokenType::String | TokenType::Number
+        )) {
+            self.heredoc_pending = false;
+            self.queued = self.heredoc_body(&delimiter);
+        }
+
+        // After an error the rest of the line becomes a single token so the parser can discard the line
+        // Lexing carries on with the next line, which matters when several lines are run at once
+        if self.failed {
+            while !['\n', '\x03'].contains(&self.cur_char) {
+                self.next_char();
             }
 
-            // Path
-            c if InputLexer::path_cond(c, self.peek_char) => {
-                match c {
-                    // Relative path
-                    '.' => {
-                        if self.peek_char == SLASH {
-                            // Relative forward path
-                            let start = self.index;
-                            self.next_char();
-                            expect_char!(self, SLASH, self.index .. self.index + 1);
+            self.queued = None;
+            self.heredoc_pending = false;
+            token = Some(Token::new(TokenType::Error, start .. self.index.min(self.source.len())));
+        }
 
-                            while self.cur_char.is_alphanumeric() || self.peek_char == SLASH {
-                                self.next_char();
-                            }
+        debug_assert!(token.is_none_or(|token| {
+            self.source.is_char_boundary(token.start) && self.source.is_char_boundary(token.end)
+        }));
 
-                            let end = self.index;
+        if let Some(token) = token {
+            self.track_braces(&token);
+        }
 
-                            return Some(Token::new(
-                                TokenType::Path,
-                                start .. end
-                            ));
-                        } else if self.peek_char == '.' {
-                            // Relative backward path
-                            let start = self.index;
-                            self.next_char();
-                            expect_char!(self, '.', self.index .. self.index + 1);
-                            expect_char!(self, SLASH, self.index .. self.index + 1, "Slashes are platform depdendant");
+        // Checks for incomplete input lex the same text again, so only the real pass is traced
+        if let Some(token) = token.filter(|token| !self.quiet && token.typ != TokenType::Whitespace && LogFlags::get().lex) {
+            eprintln!("[lex] {:?} {:?} {:?}", token.typ, token.start .. token.end, &self.source[token.start .. token.end]);
+        }
 
-                            while self.cur_char.is_alphanumeric() || self.cur_char == SLASH {
-                                self.next_char();
-                            }
+        token
+    }
 
-                            let end = self.index;
+    fn lex_token(&mut self) -> Option<Token> {
+        match self.cur_char {
+            // Number
+            // Checked before identifiers since '-' is otherwise an identifier character
+            c if c.is_ascii_digit() || (c == '-' && self.peek_char.is_ascii_digit()) => {
+                let start = self.index;
+                self.next_char();
 
-                            return Some(Token::new(
-                                TokenType::Path,
-                                start .. end
-                            ));
-                        } else {
-                            let error_offset = if self.source.len() == 1 { 1 } else { 2 };
-
-                            Report::build(ReportKind::Error, ("stdin", 0..0))
-                                .with_message("Unexpected end of path")
-                                .with_label(
-                                    Label::new(("stdin", self.index .. self.index + error_offset))
-                                        .with_message(format!("Expected relative path such as '.{}' or '..{}'", SLASH, SLASH))
-                                )
-                                .with_note("Slashes are platform dependant")
-                                .finish()
-                                .print(("stdin", Source::from(String::from_utf8(self.source.clone().into()).unwrap())))
-                                .unwrap();
-
-                            return None;
-                        }
-                    }
+                while self.cur_char.is_ascii_digit() {
+                    self.next_char();
+                }
+
+                // Only a single decimal point with digits on both sides makes a number
+                if self.cur_char == '.' && self.peek_char.is_ascii_digit() {
+                    self.next_char();
 
-                    c if (c.is_alphabetic() && self.peek_char == ':') || c == SLASH => {
-                        // Full path
-                        let start = self.index;
+                    while self.cur_char.is_ascii_digit() {
                         self.next_char();
-                        
-                        #[cfg(target_os = "windows")]
-                        {
-                            expect_char!(self, ':', self.index .. self.index + 1);
-                            expect_char!(self, SLASH, self.index .. self.index + 1, "Slashes are platform depdendant");
-                        }
+                    }
 
-                        while self.cur_char.is_alphanumeric() || self.cur_char == SLASH {
+                    if self.cur_char == '.' && self.peek_char.is_ascii_digit() {
+                        while self.cur_char.is_ascii_digit() || self.cur_char == '.' {
                             self.next_char();
                         }
 
-                        let end = self.index;
+                        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                            .with_config(Config::new().with_index_type(IndexType::Byte))
+                            .with_message("Malformed number")
+                            .with_label(
+                                Label::new((self.name, start .. self.index))
+                                    .with_message("Numbers may only contain a single decimal point")
+                            )
+                            .finish());
 
-                        return Some(Token::new(
-                            TokenType::Path,
-                            start .. end
-                        ));
+                        return None;
                     }
+                }
 
-                    _ => unimplemented!()
+                // Digits directly before a redirect such as '2>' name the file descriptor
+                if ['<', '>'].contains(&self.cur_char) && self.source[start .. self.index].bytes().all(|b| b.is_ascii_digit()) {
+                    return Some(self.redirect_token(start))
                 }
+
+                // Digits running into other word characters such as '3.txt' form an identifier or path
+                if InputLexer::is_word_char(self.cur_char) {
+                    while InputLexer::is_word_char(self.cur_char) {
+                        self.next_word_char();
+                    }
+
+                    return Some(self.word_token(start .. self.index));
+                }
+
+                Some(Token::new(
+                    TokenType::Number,
+                    start .. self.index
+                ))
             }
 
-            // String
-            '"' | '\'' => {
-                let quote_char = self.cur_char;
+            // Path
+            // Checked before identifiers since '.' and, on Windows, '/' are otherwise identifier characters
+            c if InputLexer::path_cond(c, self.peek_char) => {
                 let start = self.index;
-                self.next_char();
 
-                let mut closed = false;
+                // Drive letter such as 'C:'
+                if c.is_alphabetic() {
+                    self.next_char();
+                    self.next_char();
 
-                while self.index < self.source.len() {
-                    match self.cur_char {
-                        '\\' => {
-                            self.next_char();
-                            self.next_char();
-                        }
+                    if !SLASHES.contains(&self.cur_char) {
+                        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                            .with_config(Config::new().with_index_type(IndexType::Byte))
+                            .with_message("Invalid path")
+                            .with_label(
+                                Label::new((self.name, self.index .. self.index + self.cur_char.len_utf8()))
+                                    .with_message(format!("Expected '{}' after the drive letter", SLASHES[0]))
+                            )
+                            .with_note("Either slash may be used as a separator")
+                            .finish());
+
+                        return None;
+                    }
+                }
 
-                        c if c == quote_char => {
-                            self.next_char();
-                            closed = true;
-                            break;
-                        }
+                while InputLexer::is_word_char(self.cur_char) {
+                    self.next_word_char();
+                }
 
-                        _ => self.next_char()
-                    }
+                Some(Token::new(
+                    TokenType::Path,
+                    start .. self.index
+                ))
+            }
+
+            // Identifier
+            // Accepts IDENT_EXCEPT characters for purposes of file extensions and argv
+            c if (c.is_alphabetic() && self.peek_char != ':')
+                || IDENT_EXCEPT.contains(&c)
+                || c == '='
+                || Some(c) == ESCAPE
+                || InputLexer::is_symbol(c) =>
+            {
+                let start = self.index;
+
+                while InputLexer::is_word_char(self.cur_char) {
+                    self.next_word_char();
                 }
 
-                if !closed {
-                    Report::build(ReportKind::Error, ("stdin", 0..0))
-                        .with_message("Unexpected termination of string")
-                        .with_label(
-                            Label::new(("stdin", start .. self.index - 1))
-                                .with_message(format!("This string should be terminated with {}", quote_char))
-                        )
-                        .with_note("Keep string delimiters should be consistent")
-                        .finish()
-                        .print(("stdin", Source::from(String::from_utf8(self.source.clone().into()).unwrap())))
-                        .unwrap();
+                let end = self.index;
+
+                // 'NAME=value' and 'NAME+=value' are lexed as a single assignment token
+                let name = &self.source.as_bytes()[start .. end];
+                let name = name.strip_suffix(b"+").unwrap_or(name);
+
+                // Only the first '=' ends the name, so 'A=b=c' sets 'A' to 'b=c'
+                // The value runs to the end of the word and may mix quoted and unquoted parts, as in 'A="x y"z'
+                if self.cur_char == '=' && InputLexer::is_name(name) {
+                    self.next_char();
+
+                    while self.index < self.source.len()
+                        && !self.cur_char.is_whitespace()
+                        && !OPERATOR_CHARS.contains(&self.cur_char)
+                        && !['\0', '\x03'].contains(&self.cur_char)
+                    {
+                        if self.cur_char == '"' || self.cur_char == '\'' {
+                            self.lex_string()?;
+                        } else {
+                            self.next_word_char();
+                        }
+                    }
+
+                    return Some(Token::new(
+                        TokenType::Assignment,
+                        start .. self.index
+                    ))
+                }
 
-                    return None;
+                // Otherwise '=' is an ordinary character, as in '--opt=value' or the operand of 'test a = b'
+                while InputLexer::is_word_char(self.cur_char) || self.cur_char == '=' {
+                    self.next_word_char();
                 }
 
-                Some(Token::new(TokenType::String, start .. self.index))
+                Some(self.word_token(start .. self.index))
+            }
+
+            // String
+            // Operators inside quotes stay part of the word, so 'echo "a | b > c"' passes a single argument
+            '"' | '\'' => {
+                let span = self.lex_string()?;
+                Some(Token::new(TokenType::String, span))
+            }
+
+            // LogicalOr
+            '|' if self.peek_char == '|' => {
+                self.next_char();
+                self.next_char();
+                Some(Token::new(TokenType::LogicalOr, self.index - 2 .. self.index))
             }
 
             // Pipe
@@ -248,16 +408,50 @@ impl InputLexer {
                 Some(Token::new(TokenType::Pipe, self.index - 1 .. self.index))
             }
 
-            // RedirIn
-            '<' => {
+            // RedirIn, RedirOut and the other redirects
+            // These never need surrounding whitespace, as in 'ls>out.txt'
+            '<' | '>' => Some(self.redirect_token(self.index)),
+
+            // Semicolon
+            ';' => {
+                self.next_char();
+                Some(Token::new(TokenType::Semicolon, self.index - 1 .. self.index))
+            }
+
+            // LParen and RParen
+            '(' => {
+                self.next_char();
+                self.open_groups += 1;
+                Some(Token::new(TokenType::LParen, self.index - 1 .. self.index))
+            }
+
+            ')' => {
                 self.next_char();
-                Some(Token::new(TokenType::RedirIn, self.index - 1 .. self.index))
+                self.open_groups = self.open_groups.saturating_sub(1);
+                Some(Token::new(TokenType::RParen, self.index - 1 .. self.index))
             }
 
-            // RedirOut
-            '>' => {
+            // RedirBoth and RedirBothAppend
+            '&' if self.peek_char == '>' => {
+                let start = self.index;
+                self.next_char();
+                self.next_char();
+
+                let typ = if self.cur_char == '>' {
+                    self.next_char();
+                    TokenType::RedirBothAppend
+                } else {
+                    TokenType::RedirBoth
+                };
+
+                Some(Token::new(typ, start .. self.index))
+            }
+
+            // LogicalAnd
+            '&' if self.peek_char == '&' => {
+                self.next_char();
                 self.next_char();
-                Some(Token::new(TokenType::RedirOut, self.index - 1 .. self.index))
+                Some(Token::new(TokenType::LogicalAnd, self.index - 2 .. self.index))
             }
 
             // And
@@ -266,6 +460,20 @@ impl InputLexer {
                 Some(Token::new(TokenType::And, self.index - 1 .. self.index))
             }
 
+            // Newline
+            // Separates statements, such as when several lines are pasted at once
+            '\n' => {
+                let start = self.index;
+
+                // Here-document bodies have already been returned, so continue on the line after them
+                match self.heredoc_end.take() {
+                    Some(end) => self.seek(end),
+                    None => self.next_char()
+                }
+
+                Some(Token::new(TokenType::Newline, start .. start + 1))
+            }
+
             c if c.is_whitespace() => {
                 self.next_char();
                 Some(default_token!(Whitespace))
@@ -273,28 +481,213 @@ impl InputLexer {
 
             '\0' => Some(default_token!(EOF)),
             '\x03' => None, // This represents 0x03 END OF TEXT byte to stop any iterators
-            _ => unreachable!("No matching token implementation found for this input")
+
+            c => {
+                self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                    .with_config(Config::new().with_index_type(IndexType::Byte))
+                    .with_message("Unexpected character")
+                    .with_label(
+                        Label::new((self.name, self.index .. self.index + self.cur_char.len_utf8()))
+                            .with_message(format!("{:?} is not valid here", c))
+                    )
+                    .with_note("Wrap the argument in quotes to use it literally")
+                    .finish());
+
+                None
+            }
+        }
+    }
+
+    // Lexes a redirect operator at the current character, the token starts at 'start' to include any file descriptor
+    fn redirect_token(&mut self, start: usize) -> Token {
+        let typ = match (self.cur_char, self.peek_char) {
+            ('<', '<') => {
+                self.next_char();
+                self.next_char();
+
+                if self.cur_char == '<' {
+                    self.next_char();
+                    TokenType::HereString
+                } else {
+                    self.heredoc_pending = true;
+                    TokenType::HereDoc
+                }
+            }
+
+            ('<', '>') => {
+                self.next_char();
+                self.next_char();
+                TokenType::RedirReadWrite
+            }
+
+            ('<', _) => {
+                self.next_char();
+                TokenType::RedirIn
+            }
+
+            ('>', '>') => {
+                self.next_char();
+                self.next_char();
+                TokenType::RedirAppend
+            }
+
+            ('>', '|') => {
+                self.next_char();
+                self.next_char();
+                TokenType::RedirClobber
+            }
+
+            ('>', '&') => {
+                self.next_char();
+                self.next_char();
+                TokenType::RedirDup
+            }
+
+            _ => {
+                self.next_char();
+                TokenType::RedirOut
+            }
+        };
+
+        Token::new(typ, start .. self.index)
+    }
+
+    // Finds the body of a here-document on the lines following the current one, up to a line holding only the delimiter
+    fn heredoc_body(&mut self, delimiter: &Token) -> Option<Token> {
+        let mut word = &self.source.as_bytes()[delimiter.start .. delimiter.end];
+
+        if delimiter.typ == TokenType::String {
+            word = &word[1 .. word.len() - 1];
+        }
+
+        // Several here-documents on one line have their bodies one after another
+        let start = match self.heredoc_end {
+            Some(end) => Some(end),
+            None => self.source[self.index ..].find('\n').map(|i| self.index + i + 1)
+        };
+
+        if let Some(start) = start {
+            let mut line_start = start;
+
+            while line_start < self.source.len() {
+                let line_end = self.source[line_start ..].find('\n').map_or(self.source.len(), |i| line_start + i);
+                let line = &self.source.as_bytes()[line_start .. line_end];
+
+                if line.strip_suffix(b"\r").unwrap_or(line) == word {
+                    self.heredoc_end = Some((line_end + 1).min(self.source.len()));
+                    return Some(Token::new(TokenType::HereDocBody, start .. line_start))
+                }
+
+                line_start = line_end + 1;
+            }
         }
+
+        self.incomplete = true;
+        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Unterminated here-document")
+            .with_label(
+                Label::new((self.name, delimiter.start .. delimiter.end))
+                    .with_message(format!("Expected a line containing only '{}' to end the here-document", String::from_utf8_lossy(word)))
+            )
+            .finish());
+
+
+        None
+    }
+
+    // Classifies an unquoted word as a path if it has a file extension or a separator, otherwise an identifier
+    fn word_token(&self, span: Range<usize>) -> Token {
+        if self.source[span.clone()].contains(|c| c == '.' || SLASHES.contains(&c)) {
+            return Token::new(TokenType::Path, span)
+        }
+
+        Token::new(TokenType::Identifier, span)
+    }
+
+    // Consumes a quoted string starting at the current quote character and returns its span
+    fn lex_string(&mut self) -> Option<Range<usize>> {
+        let quote_char = self.cur_char;
+        let start = self.index;
+        self.next_char();
+
+        let mut closed = false;
+
+        while self.index < self.source.len() {
+            match self.cur_char {
+                '\\' => {
+                    self.next_char();
+                    self.next_char();
+                }
+
+                c if c == quote_char => {
+                    self.next_char();
+                    closed = true;
+                    break;
+                }
+
+                _ => self.next_char()
+            }
+        }
+
+        // The string may continue on the next line, so the shell asks for more input before reporting it
+        if !closed {
+            self.incomplete = true;
+            self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                .with_config(Config::new().with_index_type(IndexType::Byte))
+                .with_message("Unexpected termination of string")
+                .with_label(
+                    Label::new((self.name, start .. self.source.len()))
+                        .with_message(format!("This string should be terminated with {}", quote_char))
+                )
+                .with_note("Keep string delimiters should be consistent")
+                .finish());
+
+            return None;
+        }
+
+        Some(start .. self.index)
+    }
+
+    // Whether the bytes form a valid variable name
+    pub fn is_name(bytes: &[u8]) -> bool {
+        match bytes.split_first() {
+            Some((first, rest)) => {
+                (first.is_ascii_alphabetic() || *first == b'_')
+                    && rest.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'_')
+            }
+            None => false
+        }
+    }
+
+    // Characters which can continue an unquoted word, including path separators
+    #[inline(always)]
+    fn is_word_char(c: char) -> bool {
+        c.is_alphanumeric() || IDENT_EXCEPT.contains(&c) || SLASHES.contains(&c) || Some(c) == ESCAPE || InputLexer::is_symbol(c)
+    }
+
+    // Characters outside ASCII such as '✓' have no special meaning, so they're allowed in words
+    #[inline(always)]
+    fn is_symbol(c: char) -> bool {
+        !c.is_ascii() && !c.is_whitespace()
     }
 
     #[cfg(target_os = "windows")]
     #[inline(always)]
     fn path_cond(c: char, peek: char) -> bool {
-        // Not checking c == '.' && ['/', '.'].contains(&peek) here
-        // otherwise we can't generate a useful error
-        c == '.' || (c.is_alphabetic() && peek == ':')
+        SLASHES.contains(&c)
+            || (c == '.' && (SLASHES.contains(&peek) || peek == '.'))
+            || (c.is_alphabetic() && peek == ':')
     }
 
     #[cfg(target_os = "linux")]
     #[inline(always)]
-    fn path_cond(c: char, _: char) -> bool {
-        // Not checking c == '.' && ['/', '.'].contains(&peek) here
-        // otherwise we can't generate a useful error
-        c == '.' || c == '/'
+    fn path_cond(c: char, peek: char) -> bool {
+        c == '/' || (c == '.' && (peek == '/' || peek == '.'))
     }
 }
 
-impl Iterator for InputLexer {
+impl Iterator for InputLexer<'_> {
     type Item = Token;
     
     #[inline(always)]
@@ -321,6 +714,7 @@ impl Token {
 }
 
 // TODO: Can I implement sub-commands here like 'echo ${cat /home/nicholas/test.txt}'?
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TokenType {
     // Text-values
@@ -328,15 +722,33 @@ pub enum TokenType {
     Number,
     Path,
     String,
+    Assignment, // 'NAME=value'
 
     // Operators
     Pipe, // '|' - pipes stdout to stdin of following program
+    // Redirects may start with a file descriptor such as '2>'
     RedirIn, // '<' - pipes file to stdin of program
+    RedirReadWrite, // '<>' - opens a file for both reading and writing as stdin
     RedirOut, // '>' - pipes stdout to file
+    RedirAppend, // '>>' - appends stdout to file
+    RedirClobber, // '>|' - pipes stdout to file even if noclobber is set
+    RedirDup, // '>&' - points an output at another, as in '2>&1'
+    RedirBoth, // '&>' - pipes stdout and stderr to the same file
+    RedirBothAppend, // '&>>' - appends stdout and stderr to the same file
+    HereDoc, // '<<' - pipes the following lines up to a delimiter to stdin
+    HereDocBody, // The lines of a here-document, excluding the delimiter line
+    HereString, // '<<<' - pipes a single word to stdin
     And, // '&'
+    LogicalAnd, // '&&' - runs the next command if the previous succeeded
+    LogicalOr, // '||' - runs the next command if the previous failed
+    Newline, // '\n' - separates statements
+    Semicolon, // ';' - separates statements on the same line
+    LParen, // '(' - starts a group of statements run in a subshell
+    RParen, // ')' - ends a group started with '('
 
     // Special types
     // Generally used for internal reference and not an actual value
+    Error, // The rest of a line containing an error, which has already been reported
     Whitespace,
     EOF
 }
\ No newline at end of file
@@ -1,20 +1,40 @@
 use crate::ast::*;
-use super::{Token, TokenType, default_token};
-use ariadne::{Report, ReportKind, Label, Source};
+use crate::input_lexer::{Token, TokenType};
+use crate::default_token;
+use crate::log::LogFlags;
+use ariadne::{Config, IndexType, Report, ReportKind, Label, Source};
+use std::ops::Range;
+
+// Tokens which can be used as a word, such as the argument of a redirect
+const WORDS: [TokenType; 4] = [TokenType::Identifier, TokenType::Path, TokenType::String, TokenType::Number];
+
+// The highest file descriptor a redirect may name, kept well under the usual limit on open files
+const MAX_FD: usize = 255;
+
+#[cfg(target_os = "windows")]
+const NULL_DEVICE: &str = "NUL";
+#[cfg(target_os = "linux")]
+const NULL_DEVICE: &str = "/dev/null";
 
 pub struct InputParser<'a> {
+    name: &'a str,
     source: &'a str,
     tokens: Vec<Token>,
     len: usize,
-    index: usize
+    index: usize,
+    closers: Vec<&'static str>, // The token closing each group the statement being parsed is nested in, innermost last
+    failed: bool // Set when the statement being parsed has an error, which discards its whole line
 }
 
 impl<'a> InputParser<'a> {
-    pub fn new(source: &'a str, tokens: Vec<Token>) -> Self {
+    pub fn new(name: &'a str, source: &'a str, tokens: Vec<Token>) -> Self {
         Self {
+            name,
             source,
             len: tokens.len(),
             index: 0,
+            closers: Vec::new(),
+            failed: false,
             tokens
         }
     }
@@ -26,17 +46,33 @@ impl<'a> InputParser<'a> {
 
         let tok = self.tokens[self.index];
         self.index += 1;
+
+        // The lexer has already reported the error
+        if tok.typ == TokenType::Error {
+            self.failed = true;
+        }
+
         tok
     }
 
+    fn emit(&mut self, report: Report<'a, (&'a str, Range<usize>)>) {
+        report.print((self.name, Source::from(self.source))).unwrap();
+        self.failed = true;
+    }
+
     fn expect_token(&mut self, typ: &[TokenType], note: Option<&'static str>) -> Option<Token> {
         let token = self.next_token();
 
         if !typ.contains(&token.typ) {
-            let mut report = Report::build(ReportKind::Error, ("stdin", 0..0))
+            if token.typ == TokenType::Error {
+                return None;
+            }
+
+            let mut report = Report::build(ReportKind::Error, (self.name, 0..0))
+                .with_config(Config::new().with_index_type(IndexType::Byte))
                 .with_message("Invalid command")
                 .with_label(
-                    Label::new(("stdin", token.start .. token.end))
+                    Label::new((self.name, token.start .. token.end))
                         .with_message(format!("Expected {:?} token here", typ))
                 );
 
@@ -44,22 +80,203 @@ impl<'a> InputParser<'a> {
                 report = report.with_note(note);
             }
 
-            report
-                .finish()
-                .print(("stdin", Source::from(self.source)))
-                .unwrap();
-
+            self.emit(report.finish());
             return None;
         }
 
         Some(token)
     }
 
-    fn process_command(&mut self) -> Option<Spanned<Program>> {
-        let tmp = self.next_token();
+    fn peek_type(&self) -> TokenType {
+        self.tokens.get(self.index).map_or(TokenType::EOF, |token| token.typ)
+    }
+
+    // Whether the next token is the word '{' or '}', which are only special as the first word of a statement
+    fn peek_brace(&self, brace: &str) -> bool {
+        self.tokens.get(self.index).is_some_and(|token| {
+            token.typ == TokenType::Identifier && &self.source[token.start .. token.end] == brace
+        })
+    }
+
+    fn process_statement(&mut self) -> Option<Spanned<Stmt>> {
+        // Blank lines between statements, or after a trailing '&&' or '||', are skipped
+        while self.peek_type() == TokenType::Newline {
+            self.index += 1;
+        }
+
+        if self.peek_type() == TokenType::LParen {
+            return self.process_group(")")
+        }
+
+        if self.peek_brace("{") {
+            return self.process_group("}")
+        }
+
+        // A closing ')' or '}' which doesn't match the innermost open group
+        let stray = match self.closers.last() {
+            Some(&")") => self.peek_brace("}"),
+            Some(_) => self.peek_type() == TokenType::RParen,
+            None => self.peek_type() == TokenType::RParen || self.peek_brace("}")
+        };
+
+        if stray {
+            let token = self.next_token();
+            let text = &self.source[token.start .. token.end];
+
+            self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                .with_config(Config::new().with_index_type(IndexType::Byte))
+                .with_message(format!("Unexpected '{}'", text))
+                .with_label(
+                    Label::new((self.name, token.start .. token.end))
+                        .with_message(format!("There is no '{}' for this to close", if text == ")" { "(" } else { "{" }))
+                )
+                .finish());
+
+            return None
+        }
+
+        self.process_command()
+    }
+
+    // Parses '( ... )', whose statements run in a subshell, or '{ ...; }' whose statements run in the shell itself
+    // 'closer' is the token which ends the group
+    fn process_group(&mut self, closer: &'static str) -> Option<Spanned<Stmt>> {
+        let open = self.next_token();
+
+        // Groups run inside the shell, so there's no process to connect a pipe to
+        if self.index >= 2 && self.tokens[self.index - 2].typ == TokenType::Pipe {
+            self.unsupported_group(open.start .. open.end, "A group can't be piped into");
+            return None
+        }
+
+        self.closers.push(closer);
+        let mut stmts = Vec::new();
+
+        loop {
+            while self.peek_type() == TokenType::Newline {
+                self.index += 1;
+            }
+
+            let closed = match closer {
+                ")" => self.peek_type() == TokenType::RParen,
+                _ => self.peek_brace("}")
+            };
+
+            if closed {
+                break;
+            }
+
+            if self.peek_type() == TokenType::EOF {
+                // '}' is only recognised as the first word of a statement, so 'echo a }' doesn't close a group
+                let note = match closer {
+                    ")" => "Expected ')' to close this group",
+                    _ => "Expected '}' to close this group, after a ';' or a newline"
+                };
+
+                self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                    .with_config(Config::new().with_index_type(IndexType::Byte))
+                    .with_message("Unterminated group")
+                    .with_label(
+                        Label::new((self.name, open.start .. open.end))
+                            .with_message(note)
+                    )
+                    .finish());
+
+                return None
+            }
+
+            stmts.push(self.process_statement()?);
+        }
+
+        self.closers.pop();
+        let close = self.next_token();
+        let next = self.next_token();
+
+        let connector = match Self::connector(next.typ) {
+            Some(connector) => connector,
+            None if next.typ == TokenType::RParen => {
+                self.index -= 1;
+                Connector::Sequence
+            }
+            None if next.typ == TokenType::Error => return None,
+            None => {
+                self.unsupported_group(next.start .. next.end, "Groups can't be piped or redirected");
+                return None
+            }
+        };
+
+        Some(Spanned::new(Stmt::Group(Group {
+            body: Module { stmts },
+            subshell: closer == ")",
+            connector
+        }), open.start .. close.end))
+    }
+
+    fn unsupported_group(&mut self, span: Range<usize>, message: &str) {
+        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Unsupported group operation")
+            .with_label(Label::new((self.name, span)).with_message(message))
+            .finish());
+    }
+
+    fn process_command(&mut self) -> Option<Spanned<Stmt>> {
+        let mut tmp = self.next_token();
+
         if tmp.typ == TokenType::EOF {
             return None
         }
+
+        let start = tmp.start;
+        let mut env = Vec::new();
+
+        while tmp.typ == TokenType::Assignment {
+            let eq = tmp.start + self.source[tmp.start .. tmp.end].find('=').unwrap();
+            env.push(Assignment { name: tmp.start .. eq, value: eq + 1 .. tmp.end });
+            tmp = self.next_token();
+        }
+
+        // A ')' closing a group ends the statement but is left for the group to consume
+        let connector = match tmp.typ {
+            TokenType::RParen => Some(Connector::Sequence),
+            typ => Self::connector(typ)
+        };
+
+        // Assignments without a command set shell variables rather than a program's environment
+        if let Some(connector) = connector.filter(|_| !env.is_empty()) {
+            if tmp.typ == TokenType::RParen {
+                self.index -= 1;
+            }
+
+            let end = env.last().unwrap().value.end;
+
+            return Some(Spanned::new(Stmt::Assign(Assign {
+                assignments: env,
+                connector
+            }), start .. end))
+        }
+
+        // An operator where the command should be, as in '| sort' or 'ls ; ; ls'
+        if matches!(tmp.typ, TokenType::Pipe | TokenType::And | TokenType::LogicalAnd | TokenType::LogicalOr | TokenType::Semicolon) {
+            self.missing_command_before(tmp);
+            return None
+        }
+
+        // A number can't name a program, though a path to one such as './42' can
+        if tmp.typ == TokenType::Number {
+            self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                .with_config(Config::new().with_index_type(IndexType::Byte))
+                .with_message("Invalid command")
+                .with_label(
+                    Label::new((self.name, tmp.start .. tmp.end))
+                        .with_message(format!("'{}' is a number, not a command", &self.source[tmp.start .. tmp.end]))
+                )
+                .with_note(format!("To run a program with this name, give its path as in './{}'", &self.source[tmp.start .. tmp.end]))
+                .finish());
+
+            return None
+        }
+
         self.index -= 1;
 
         let cmd = self.expect_token(
@@ -67,63 +284,460 @@ impl<'a> InputParser<'a> {
             Some("This was not recognized as an internal or external command")
         )?;
 
+        // The span covers the command, its arguments and any redirect target but never a trailing operator
+        let mut end = cmd.end;
         let mut argv = Vec::new();
-        let mut token = self.next_token();
-        while ![TokenType::EOF, TokenType::And, TokenType::Pipe, TokenType::RedirIn, TokenType::RedirOut].contains(&token.typ) {
-            argv.push(token.start .. token.end);
-            token = self.next_token();
-        }
+        let mut redirects = Vec::new();
+        let mut piped = false;
+        let mut stdout_redirect = None; // Span of the stdout redirect, to report conflicts with a following pipe
+        let mut both_redirect = None; // A '&>' or '&>>' and its span, which no other redirect of stdout or stderr may follow
 
-        let mut stdin = StreamStrategy::Inherit;
-        let mut stdout = StreamStrategy::Inherit;
+        let connector = loop {
+            let token = self.next_token();
 
-        match token.typ {
-            TokenType::Pipe => {
-                stdin = StreamStrategy::Inherit;
-                stdout = StreamStrategy::PipeToStdin
-            }
+            match token.typ {
+                TokenType::Pipe => {
+                    // Output can't go to both a file and the next stage
+                    if let Some(redirect) = stdout_redirect {
+                        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                            .with_config(Config::new().with_index_type(IndexType::Byte))
+                            .with_message("Conflicting redirects")
+                            .with_label(
+                                Label::new((self.name, redirect))
+                                    .with_message("stdout is redirected to a file here")
+                            )
+                            .with_label(
+                                Label::new((self.name, token.start .. token.end))
+                                    .with_message("but is also piped to the next command here")
+                            )
+                            .with_note("Remove either the redirect or the pipe")
+                            .finish());
 
-            TokenType::RedirIn => {
-                let file_handle = self.expect_token(
-                    &[TokenType::Path],
-                    Some("You must provide the path to a file to redirect to stdin")
-                )?;
-                stdin = StreamStrategy::PipeFromFile(file_handle.start .. file_handle.end);
-                stdout = StreamStrategy::Inherit;
-            }
+                        return None;
+                    }
+
+                    if Self::ends_stage(self.peek_past_newlines()) {
+                        self.missing_command_after(token, "Nothing follows the pipe to read this command's output", "Add the command to pipe into, as in 'ls | sort', or remove the '|'");
+                        return None
+                    }
+
+                    piped = true;
+                    break Connector::Sequence
+                }
+
+                TokenType::RedirIn | TokenType::RedirReadWrite | TokenType::HereDoc | TokenType::HereString => {
+                    let fd = self.redirect_fd(&token, 0)?;
+
+                    let strategy = match token.typ {
+                        TokenType::RedirIn => {
+                            let file_handle = self.expect_target(
+                                &token,
+                                &[TokenType::Path, TokenType::Identifier, TokenType::String],
+                                "You must provide the path to a file to redirect to stdin"
+                            )?;
+                            end = file_handle.end;
+                            StreamStrategy::PipeFromFile(file_handle.start .. file_handle.end)
+                        }
+
+                        TokenType::RedirReadWrite => {
+                            let file_handle = self.expect_target(
+                                &token,
+                                &[TokenType::Path, TokenType::Identifier, TokenType::String],
+                                "You must provide the path to a file to open for reading and writing"
+                            )?;
+                            end = file_handle.end;
+                            StreamStrategy::ReadWriteFile(file_handle.start .. file_handle.end)
+                        }
 
-            TokenType::RedirOut => {
-                let file_handle = self.expect_token(
-                    &[TokenType::Path],
-                    Some("You must provide the path to a file to redirect stdout to")
-                )?;
-                stdin = StreamStrategy::Inherit;
-                stdout = StreamStrategy::PipeToFile(file_handle.start .. file_handle.end)
+                        TokenType::HereString => {
+                            let word = self.expect_target(
+                                &token,
+                                &WORDS,
+                                "You must provide the text to pass to stdin"
+                            )?;
+                            end = word.end;
+                            StreamStrategy::HereString(word.start .. word.end)
+                        }
+
+                        // The lexer places the body straight after the delimiter
+                        _ => {
+                            let delimiter = self.expect_target(
+                                &token,
+                                &WORDS,
+                                "You must provide the word which ends the here-document"
+                            )?;
+                            let body = self.next_token();
+                            end = delimiter.end;
+
+                            // Quoting the delimiter leaves the body exactly as written
+                            StreamStrategy::HereDoc(body.start .. body.end, delimiter.typ != TokenType::String)
+                        }
+                    };
+
+                    redirects.push(Redirect { fd, strategy, span: token.start .. end });
+                }
+
+                TokenType::RedirOut | TokenType::RedirClobber | TokenType::RedirAppend => {
+                    let fd = self.redirect_fd(&token, 1)?;
+
+                    let file_handle = self.expect_target(
+                        &token,
+                        &[TokenType::Path, TokenType::Identifier, TokenType::String],
+                        "You must provide the path to a file to redirect output to"
+                    )?;
+
+                    // The null device gets its own strategy so the engine can skip opening a file
+                    let strategy = if Self::is_null_device(&self.source[file_handle.start .. file_handle.end]) {
+                        StreamStrategy::Discard
+                    } else if token.typ == TokenType::RedirAppend {
+                        StreamStrategy::AppendToFile(file_handle.start .. file_handle.end)
+                    } else {
+                        StreamStrategy::PipeToFile(file_handle.start .. file_handle.end, token.typ == TokenType::RedirClobber)
+                    };
+
+                    if fd == 1 {
+                        stdout_redirect = Some(token.start .. file_handle.end);
+                    }
+
+                    let redirect = Redirect { fd, strategy, span: token.start .. file_handle.end };
+                    self.check_both_redirect(&redirect, both_redirect.as_ref())?;
+
+                    redirects.push(redirect);
+                    end = file_handle.end;
+                }
+
+                // Sends stdout to the file and then points stderr at it, as 'file 2>&1' would
+                TokenType::RedirBoth | TokenType::RedirBothAppend => {
+                    let file_handle = self.expect_target(
+                        &token,
+                        &[TokenType::Path, TokenType::Identifier, TokenType::String],
+                        "You must provide the path to a file to redirect output to"
+                    )?;
+
+                    let span = token.start .. file_handle.end;
+
+                    if let Some(other) = redirects.iter().find(|redirect| matches!(redirect.fd, 1 | 2)) {
+                        self.report_both_conflict(other, span, &token);
+                        return None
+                    }
+
+                    let strategy = if Self::is_null_device(&self.source[file_handle.start .. file_handle.end]) {
+                        StreamStrategy::Discard
+                    } else if token.typ == TokenType::RedirBothAppend {
+                        StreamStrategy::AppendToFile(file_handle.start .. file_handle.end)
+                    } else {
+                        StreamStrategy::PipeToFile(file_handle.start .. file_handle.end, false)
+                    };
+
+                    redirects.push(Redirect { fd: 1, strategy, span: span.clone() });
+                    redirects.push(Redirect { fd: 2, strategy: StreamStrategy::Duplicate(1), span: span.clone() });
+
+                    stdout_redirect = Some(span.clone());
+                    both_redirect = Some((token, span));
+                    end = file_handle.end;
+                }
+
+                TokenType::RedirDup => {
+                    let fd = self.redirect_fd(&token, 1)?;
+
+                    let target = self.expect_target(
+                        &token,
+                        &[TokenType::Number],
+                        "You must provide the descriptor to point output at, such as the '1' in '2>&1'"
+                    )?;
+
+                    // The handle is shared as it is at this point, so in '2>&1 > file' only stdout goes to the file
+                    match self.source[target.start .. target.end].parse::<usize>() {
+                        Ok(other) if other <= MAX_FD => {
+                            if fd == 1 {
+                                stdout_redirect = Some(token.start .. target.end);
+                            }
+
+                            let redirect = Redirect { fd, strategy: StreamStrategy::Duplicate(other), span: token.start .. target.end };
+                            self.check_both_redirect(&redirect, both_redirect.as_ref())?;
+
+                            redirects.push(redirect);
+                        }
+                        _ => {
+                            self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+                                .with_config(Config::new().with_index_type(IndexType::Byte))
+                                .with_message("Unsupported file descriptor")
+                                .with_label(
+                                    Label::new((self.name, target.start .. target.end))
+                                        .with_message(format!("Descriptors go up to {}", MAX_FD))
+                                )
+                                .finish());
+
+                            return None
+                        }
+                    }
+
+                    end = target.end;
+                }
+
+                TokenType::RParen => {
+                    self.index -= 1;
+                    break Connector::Sequence
+                }
+
+                typ => match Self::connector(typ) {
+                    // A connector which needs a command after it, as in a trailing 'make &&'
+                    Some(Connector::And | Connector::Or) if Self::ends_stage(self.peek_past_newlines()) => {
+                        let note = match typ {
+                            TokenType::LogicalAnd => "Add the command to run when this one succeeds, or remove the '&&'",
+                            _ => "Add the command to run when this one fails, or remove the '||'"
+                        };
+
+                        self.missing_command_after(token, "Nothing follows this to run", note);
+                        return None
+                    }
+
+                    Some(connector) => break connector,
+                    None => {
+                        argv.push(token.start .. token.end);
+                        end = token.end;
+                    }
+                }
             }
+        };
 
-            TokenType::EOF | TokenType::And => {
-                // Correct the span if there are no pipes or redirects (which would cause EOF with span of 0 .. 0)
-                token.end = cmd.end;
+        Some(Spanned::new(Stmt::Command(Program::new(
+            cmd.start .. cmd.end,
+            argv,
+            env,
+            redirects,
+            piped,
+            connector
+        )), start .. end))
+    }
+
+    // Refuses a redirect of stdout or stderr after a '&>' or '&>>', which already sent both to a file
+    fn check_both_redirect(&mut self, redirect: &Redirect, both: Option<&(Token, Range<usize>)>) -> Option<()> {
+        let Some((op, span)) = both.filter(|_| matches!(redirect.fd, 1 | 2)) else {
+            return Some(())
+        };
+
+        self.report_both_conflict(redirect, span.clone(), op);
+        None
+    }
+
+    fn report_both_conflict(&mut self, other: &Redirect, both: Range<usize>, op: &Token) {
+        let text = &self.source[op.start .. op.end];
+        let stream = if other.fd == 1 { "stdout" } else { "stderr" };
+
+        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Conflicting redirects")
+            .with_label(
+                Label::new((self.name, other.span.clone()))
+                    .with_message(format!("{} is redirected here", stream))
+            )
+            .with_label(
+                Label::new((self.name, both))
+                    .with_message(format!("but '{}' also sends stdout and stderr to this file", text))
+            )
+            .with_note(format!("Remove one of the redirects, '{}' already covers both outputs", text))
+            .finish());
+    }
+
+    // Expects the word after a redirect operator, suggesting what belongs there when the command ends right after it
+    fn expect_target(&mut self, op: &Token, typ: &[TokenType], note: &'static str) -> Option<Token> {
+        if !Self::ends_stage(self.peek_type()) && !Self::is_redirect(self.peek_type()) {
+            return self.expect_token(typ, Some(note))
+        }
+
+        let text = &self.source[op.start .. op.end];
+
+        let fix = match op.typ {
+            TokenType::RedirIn => format!("Add the file to read from, as in '{} input.txt'", text),
+            TokenType::RedirReadWrite => format!("Add the file to open, as in '{} data.txt'", text),
+            TokenType::RedirDup => format!("Add the descriptor to share, as in '{}1'", text),
+            TokenType::HereString => format!("Add the text to pass in, as in '{} \"text\"'", text),
+            TokenType::HereDoc => format!("Add the word which ends the here-document, as in '{} EOF'", text),
+            _ => format!("Add the file to write to, as in '{} out.txt', or '{} {}' to discard the output", text, text, NULL_DEVICE)
+        };
+
+        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Missing redirect target")
+            .with_label(
+                Label::new((self.name, op.start .. op.end))
+                    .with_message(format!("'{}' needs a word after it", text))
+            )
+            .with_note(fix)
+            .finish());
+
+        None
+    }
+
+    fn missing_command_before(&mut self, op: Token) {
+        let text = &self.source[op.start .. op.end];
+
+        let note = match op.typ {
+            TokenType::Pipe => "A pipe sends the output of the command before it to the one after, as in 'ls | sort'",
+            TokenType::Semicolon => "Remove the extra ';'",
+            _ => "Add the command this follows, as in 'make && make install'"
+        };
+
+        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Missing command")
+            .with_label(
+                Label::new((self.name, op.start .. op.end))
+                    .with_message(format!("There is no command before '{}'", text))
+            )
+            .with_note(note)
+            .finish());
+    }
+
+    fn missing_command_after(&mut self, op: Token, label: &str, note: &str) {
+        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message(format!("Missing command after '{}'", &self.source[op.start .. op.end]))
+            .with_label(
+                Label::new((self.name, op.start .. op.end))
+                    .with_message(label)
+            )
+            .with_note(note)
+            .finish());
+    }
+
+    // The next token which isn't a line break, since a command may continue on the line after '|', '&&' or '||'
+    fn peek_past_newlines(&self) -> TokenType {
+        self.tokens[self.index.min(self.len) ..]
+            .iter()
+            .find(|token| token.typ != TokenType::Newline)
+            .map_or(TokenType::EOF, |token| token.typ)
+    }
+
+    // Whether a token ends a command or pipeline stage, where a word or command was still expected
+    fn ends_stage(typ: TokenType) -> bool {
+        Self::connector(typ).is_some() || matches!(typ, TokenType::Pipe | TokenType::RParen)
+    }
+
+    fn is_redirect(typ: TokenType) -> bool {
+        matches!(
+            typ,
+            TokenType::RedirIn | TokenType::RedirReadWrite | TokenType::RedirOut | TokenType::RedirAppend
+                | TokenType::RedirClobber | TokenType::RedirDup | TokenType::RedirBoth | TokenType::RedirBothAppend
+                | TokenType::HereDoc | TokenType::HereString
+        )
+    }
+
+    // The file descriptor a redirect applies to, which is written before the operator as in '2>' or '3<'
+    // Descriptors above 2 are passed on to programs as they are, which only Unix supports
+    fn redirect_fd(&mut self, token: &Token, default: usize) -> Option<usize> {
+        let text = &self.source[token.start .. token.end];
+        let digits = text.trim_end_matches(['<', '>', '|', '&']);
+
+        if digits.is_empty() {
+            return Some(default)
+        }
+
+        let fd = digits.parse::<usize>().ok().filter(|fd| *fd <= MAX_FD);
+
+        let note = match fd {
+            None => format!("Descriptors go up to {}", MAX_FD),
+            Some(fd) if fd > 2 && cfg!(windows) => String::from("Windows programs only have stdin, stdout and stderr, which are 0, 1 and 2"),
+            Some(fd) if fd != 0 && matches!(token.typ, TokenType::HereDoc | TokenType::HereString) => {
+                String::from("Here-documents and here-strings can only be given to stdin, which is 0")
             }
+            Some(fd) => return Some(fd)
+        };
+
+        self.emit(Report::build(ReportKind::Error, (self.name, 0..0))
+            .with_config(Config::new().with_index_type(IndexType::Byte))
+            .with_message("Unsupported file descriptor")
+            .with_label(
+                Label::new((self.name, token.start .. token.start + digits.len()))
+                    .with_message(format!("'{}' can't be redirected with '{}'", digits, &text[digits.len() ..]))
+            )
+            .with_note(note)
+            .finish());
 
-            _ => unreachable!()
+        None
+    }
+
+    // Maps tokens which end a statement to how the following statement is run
+    fn connector(typ: TokenType) -> Option<Connector> {
+        match typ {
+            TokenType::EOF | TokenType::Newline | TokenType::Semicolon => Some(Connector::Sequence),
+            TokenType::And => Some(Connector::Background),
+            TokenType::LogicalAnd => Some(Connector::And),
+            TokenType::LogicalOr => Some(Connector::Or),
+            _ => None
         }
+    }
 
-        Some(Spanned::new(Program::new(
-            cmd.start .. cmd.end,
-            argv,
-            stdin,
-            stdout
-        ), cmd.start .. token.end))
+    #[cfg(target_os = "windows")]
+    fn is_null_device(path: &str) -> bool {
+        // Windows device names are case-insensitive
+        path.eq_ignore_ascii_case(NULL_DEVICE)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn is_null_device(path: &str) -> bool {
+        path == NULL_DEVICE
+    }
+
+    // Whether the last token consumed ended a line
+    fn after_newline(&self) -> bool {
+        self.index > 0 && self.tokens.get(self.index - 1).is_some_and(|token| token.typ == TokenType::Newline)
     }
 
     pub fn build_ast(&mut self) -> Module {
         let mut stmts = Vec::new();
+        let mut line_start = 0;
+
+        loop {
+            let cmd = self.process_statement();
+
+            // Like other shells a line with an error is discarded as a whole, and parsing resumes on the next line
+            if self.failed {
+                self.failed = false;
+                self.closers.clear();
+                stmts.truncate(line_start);
+
+                while !self.after_newline() && self.index < self.len {
+                    self.index += 1;
+                }
+
+                continue;
+            }
+
+            let Some(cmd) = cmd else {
+                break
+            };
+
+            if LogFlags::get().parse {
+                eprintln!("[parse] {:?}", cmd);
+            }
 
-        while let Some(cmd) = self.process_command() {
             stmts.push(cmd);
+
+            if self.after_newline() {
+                line_start = stmts.len();
+            }
         }
 
+        // The engine slices every range out of the source, so each must fall on a character boundary
+        debug_assert!(self.on_char_boundaries(&stmts));
+
         Module { stmts }
     }
+
+    fn on_char_boundaries(&self, stmts: &[Spanned<Stmt>]) -> bool {
+        stmts.iter().all(|stmt| {
+            let ranges: Vec<&Range<usize>> = match &stmt.value {
+                Stmt::Command(program) => std::iter::once(&program.program).chain(&program.argv).collect(),
+                Stmt::Group(group) if !self.on_char_boundaries(&group.body.stmts) => return false,
+                Stmt::Group(_) => Vec::new(),
+                Stmt::Assign(assign) => assign.assignments.iter().flat_map(|assignment| [&assignment.name, &assignment.value]).collect()
+            };
+
+            std::iter::once(&stmt.span)
+                .chain(ranges)
+                .all(|range| self.source.is_char_boundary(range.start) && self.source.is_char_boundary(range.end))
+        })
+    }
 }
\ No newline at end of file
@@ -0,0 +1,426 @@
+use std::io::{self, Write};
+
+// Text offered by Tab to replace the end of the word before the cursor
+pub struct Completion {
+    pub start: usize, // Byte offset in the line where the replaced text starts, it ends at the cursor
+    pub candidates: Vec<String>
+}
+
+// How long to wait after Esc for the rest of a key's escape sequence before taking it as Esc on its own
+#[cfg(target_os = "linux")]
+const ESCAPE_TIMEOUT_MS: i32 = 50;
+
+// Reads a line typed at the terminal with basic editing, appending it to 'line' like 'read_line' would
+// The terminal is only taken out of canonical mode while the line is edited, so programs run from the shell see it
+// as they normally would. 'history' is searched by Ctrl+R, oldest first. Returns 0 when Ctrl+D is pressed on an empty line.
+#[cfg(target_os = "linux")]
+pub fn read_line(
+    prompt: &str,
+    line: &mut String,
+    history: &[String],
+    complete: impl Fn(&str, usize) -> Option<Completion>,
+    abbreviation: impl Fn(&str) -> Option<String>
+) -> io::Result<usize> {
+    let _raw = RawMode::enable()?;
+    let mut editor = Editor { prompt, buffer: String::new(), cursor: 0, stdout: io::stdout() };
+    let mut pending = None;
+
+    editor.redraw()?;
+
+    loop {
+        // A key which ended a search is handled as if it had just been pressed
+        let Some(byte) = pending.take().map_or_else(read_byte, |byte| Ok(Some(byte)))? else {
+            return Ok(0)
+        };
+
+        match byte {
+            // An abbreviation is expanded when the line is run as well, so what runs is what's shown
+            b'\r' | b'\n' => {
+                editor.expand_abbreviation(&abbreviation);
+                editor.redraw()?;
+                write!(editor.stdout, "\r\n")?;
+                editor.stdout.flush()?;
+
+                line.push_str(&editor.buffer);
+                line.push('\n');
+                return Ok(editor.buffer.len() + 1)
+            }
+
+            // Ctrl+C drops the line being typed rather than interrupting the shell
+            0x03 => {
+                write!(editor.stdout, "^C\r\n")?;
+                editor.stdout.flush()?;
+
+                line.push('\n');
+                return Ok(1)
+            }
+
+            0x04 if editor.buffer.is_empty() => {
+                write!(editor.stdout, "\r\n")?;
+                editor.stdout.flush()?;
+                return Ok(0)
+            }
+
+            0x04 => editor.delete(),
+            0x7f | 0x08 => editor.backspace(),
+            0x01 => editor.cursor = 0,
+            0x05 => editor.cursor = editor.buffer.len(),
+            b'\t' => editor.complete(&complete)?,
+            b' ' => {
+                editor.expand_abbreviation(&abbreviation);
+                editor.buffer.insert(editor.cursor, ' ');
+                editor.cursor += 1;
+            }
+
+            0x12 => pending = editor.search(history)?,
+            0x1b => editor.escape_sequence()?,
+
+            // Other control characters have no binding
+            byte if byte < 0x20 => continue,
+
+            byte => {
+                if let Some(c) = read_char(byte)? {
+                    editor.buffer.insert(editor.cursor, c);
+                    editor.cursor += c.len_utf8();
+                }
+            }
+        }
+
+        editor.redraw()?;
+    }
+}
+
+// The console has no canonical mode to switch out of here, so lines are read without editing or completion
+#[cfg(target_os = "windows")]
+pub fn read_line(
+    prompt: &str,
+    line: &mut String,
+    _history: &[String],
+    _complete: impl Fn(&str, usize) -> Option<Completion>,
+    _abbreviation: impl Fn(&str) -> Option<String>
+) -> io::Result<usize> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    crate::engine::read_stdin_line(line)
+}
+
+#[cfg(target_os = "linux")]
+struct Editor<'a> {
+    prompt: &'a str,
+    buffer: String,
+    cursor: usize, // Byte offset in 'buffer', always on a char boundary
+    stdout: io::Stdout
+}
+
+#[cfg(target_os = "linux")]
+impl Editor<'_> {
+    // Rewrites the whole line and puts the cursor back, which keeps wide edits such as a completion simple
+    fn redraw(&mut self) -> io::Result<()> {
+        write!(self.stdout, "\r{}{}\x1b[K", self.prompt, self.buffer)?;
+
+        let after = self.buffer[self.cursor..].chars().count();
+
+        if after > 0 {
+            write!(self.stdout, "\x1b[{}D", after)?;
+        }
+
+        self.stdout.flush()
+    }
+
+    fn backspace(&mut self) {
+        if let Some(c) = self.buffer[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.buffer.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.buffer.len() {
+            self.buffer.remove(self.cursor);
+        }
+    }
+
+    fn left(&mut self) {
+        if let Some(c) = self.buffer[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    fn right(&mut self) {
+        if let Some(c) = self.buffer[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    // Handles the arrow, Home, End and Delete keys, other sequences are read in full and ignored
+    fn escape_sequence(&mut self) -> io::Result<()> {
+        let Some(kind @ (b'[' | b'O')) = read_byte()? else {
+            return Ok(())
+        };
+
+        let mut params = Vec::new();
+
+        let last = loop {
+            match read_byte()? {
+                Some(byte @ 0x40 ..= 0x7e) => break byte,
+                Some(byte) if kind == b'[' => params.push(byte),
+                _ => return Ok(())
+            }
+        };
+
+        match (last, params.as_slice()) {
+            (b'C', _) => self.right(),
+            (b'D', _) => self.left(),
+            (b'H', _) | (b'~', b"1" | b"7") => self.cursor = 0,
+            (b'F', _) | (b'~', b"4" | b"8") => self.cursor = self.buffer.len(),
+            (b'~', b"3") => self.delete(),
+            _ => ()
+        }
+
+        Ok(())
+    }
+
+    // The first word of the line is replaced with the abbreviation it matches once it's finished,
+    // so the command can be checked or edited before it runs
+    fn expand_abbreviation(&mut self, abbreviation: &impl Fn(&str) -> Option<String>) {
+        let start = self.buffer.len() - self.buffer.trim_start().len();
+        let after = &self.buffer[self.cursor..];
+
+        if self.cursor <= start || !(after.is_empty() || after.starts_with(char::is_whitespace)) {
+            return
+        }
+
+        let word = &self.buffer[start .. self.cursor];
+
+        if word.contains(char::is_whitespace) {
+            return
+        }
+
+        if let Some(expansion) = abbreviation(word) {
+            self.buffer.replace_range(start .. self.cursor, &expansion);
+            self.cursor = start + expansion.len();
+        }
+    }
+
+    // A single match replaces the word, several are narrowed to what they share, or listed when nothing is shared
+    fn complete(&mut self, complete: &impl Fn(&str, usize) -> Option<Completion>) -> io::Result<()> {
+        let Some(Completion { start, candidates }) = complete(&self.buffer, self.cursor) else {
+            return self.bell()
+        };
+
+        let Some(first) = candidates.first() else {
+            return self.bell()
+        };
+
+        let shared = candidates.iter().fold(first.as_str(), |shared, candidate| common_prefix(shared, candidate));
+
+        if shared.len() > self.cursor - start {
+            self.buffer.replace_range(start .. self.cursor, shared);
+            self.cursor = start + shared.len();
+        } else if candidates.len() > 1 {
+            write!(self.stdout, "\r\n{}\r\n", candidates.join("  "))?;
+        }
+
+        Ok(())
+    }
+
+    // Ctrl+R finds the newest line containing what's typed, and pressing it again finds the next older one
+    // Enter or another control key takes the line found, returning the key for the caller to act on,
+    // while Esc or Ctrl+G puts back the line from before the search
+    fn search(&mut self, history: &[String]) -> io::Result<Option<u8>> {
+        let original = (self.buffer.clone(), self.cursor);
+        let mut query = String::new();
+        let mut found: Option<usize> = None; // Index into 'history' of the line shown
+        let mut failed = false;
+
+        loop {
+            self.redraw_search(&query, found.map(|index| history[index].as_str()), failed)?;
+
+            let Some(byte) = read_byte()? else {
+                return Ok(None)
+            };
+
+            // Where to look back from, the line shown is still a match when the query grows
+            let before = match byte {
+                0x12 => found.unwrap_or(history.len()),
+                0x7f | 0x08 => {
+                    query.pop();
+                    history.len()
+                }
+
+                0x07 => return self.cancel_search(original),
+
+                // Esc on its own cancels, but a key such as an arrow takes the line found and is then handled
+                0x1b if !input_waiting(ESCAPE_TIMEOUT_MS) => return self.cancel_search(original),
+                0x1b => {
+                    self.accept_search(history, found);
+                    self.escape_sequence()?;
+                    return Ok(None)
+                }
+
+                byte if byte < 0x20 => {
+                    self.accept_search(history, found);
+                    return Ok(Some(byte))
+                }
+
+                byte => {
+                    if let Some(c) = read_char(byte)? {
+                        query.push(c);
+                    }
+
+                    found.map_or(history.len(), |index| index + 1)
+                }
+            };
+
+            if query.is_empty() {
+                (found, failed) = (None, false);
+                continue;
+            }
+
+            // Repeats of the line already shown are skipped so each Ctrl+R shows something new
+            let current = found.map(|index| history[index].as_str());
+            let older = history[..before].iter().rposition(|entry| entry.contains(&query) && (byte != 0x12 || Some(entry.as_str()) != current));
+
+            // A failed search keeps showing the last match, like other shells
+            failed = older.is_none();
+            found = older.or(found);
+        }
+    }
+
+    fn accept_search(&mut self, history: &[String], found: Option<usize>) {
+        if let Some(index) = found {
+            self.buffer = history[index].clone();
+            self.cursor = self.buffer.len();
+        }
+    }
+
+    fn cancel_search(&mut self, (buffer, cursor): (String, usize)) -> io::Result<Option<u8>> {
+        self.buffer = buffer;
+        self.cursor = cursor;
+        Ok(None)
+    }
+
+    // Shows the search in place of the prompt with the cursor where the match starts
+    fn redraw_search(&mut self, query: &str, found: Option<&str>, failed: bool) -> io::Result<()> {
+        let found = found.unwrap_or("");
+        let status = if failed { "failed " } else { "" };
+
+        write!(self.stdout, "\r({}reverse-i-search)`{}': {}\x1b[K", status, query, found)?;
+
+        if let Some(start) = found.find(query).filter(|_| !query.is_empty()) {
+            let after = found[start..].chars().count();
+
+            if after > 0 {
+                write!(self.stdout, "\x1b[{}D", after)?;
+            }
+        }
+
+        self.stdout.flush()
+    }
+
+    fn bell(&mut self) -> io::Result<()> {
+        write!(self.stdout, "\x07")
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn common_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let len = a.char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or(a.len().min(b.len()), |((i, _), _)| i);
+
+    &a[..len]
+}
+
+// Reads the rest of a UTF-8 character started by 'first', invalid input is dropped
+#[cfg(target_os = "linux")]
+fn read_char(first: u8) -> io::Result<Option<char>> {
+    let len = match first {
+        0xc0 ..= 0xdf => 2,
+        0xe0 ..= 0xef => 3,
+        0xf0 ..= 0xf7 => 4,
+        _ => 1
+    };
+
+    let mut bytes = vec![first];
+
+    while bytes.len() < len {
+        match read_byte()? {
+            Some(byte) => bytes.push(byte),
+            None => break
+        }
+    }
+
+    Ok(std::str::from_utf8(&bytes).ok().and_then(|text| text.chars().next()))
+}
+
+// Whether a key arrives within 'timeout_ms'
+#[cfg(target_os = "linux")]
+fn input_waiting(timeout_ms: i32) -> bool {
+    let mut fds = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+
+    // Safety: 'fds' is a single valid pollfd
+    unsafe { libc::poll(&mut fds, 1, timeout_ms) > 0 && fds.revents & libc::POLLIN != 0 }
+}
+
+#[cfg(target_os = "linux")]
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = 0u8;
+
+    loop {
+        // Safety: 'byte' is a valid buffer for the single byte being read
+        match unsafe { libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) } {
+            0 => return Ok(None),
+            1 => return Ok(Some(byte)),
+            _ => {
+                let err = io::Error::last_os_error();
+
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err)
+                }
+            }
+        }
+    }
+}
+
+// Keys are read one at a time without echo while this is alive, the previous settings come back when it's dropped
+#[cfg(target_os = "linux")]
+struct RawMode {
+    original: libc::termios
+}
+
+#[cfg(target_os = "linux")]
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        // Safety: termios is plain data which 'tcgetattr' fills in before it's read
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+
+        // Safety: 'original' is a valid termios for the call to write to
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+
+        // Safety: 'raw' is a copy of the terminal's own settings with some flags cleared
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(Self { original })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // Safety: 'original' holds the settings read from the terminal when raw mode was enabled
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::OnceLock;
+
+// Stages which print tracing to stderr, chosen with a comma separated list such as 'PHOENIX_LOG=lex,parse,exec'
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogFlags {
+    pub lex: bool, // Every token with its span
+    pub parse: bool, // Every statement the parser builds
+    pub exec: bool // Every program spawned with its arguments and redirects
+}
+
+static FLAGS: OnceLock<LogFlags> = OnceLock::new();
+
+impl LogFlags {
+    fn from_env() -> Self {
+        let mut flags = Self::default();
+
+        let Some(value) = std::env::var_os("PHOENIX_LOG") else {
+            return flags
+        };
+
+        for stage in value.to_string_lossy().split(',') {
+            match stage.trim() {
+                "lex" => flags.lex = true,
+                "parse" => flags.parse = true,
+                "exec" => flags.exec = true,
+                "all" => flags = Self { lex: true, parse: true, exec: true },
+                "" => (),
+                other => eprintln!("Phoenix: warning: unknown PHOENIX_LOG stage '{}'", other)
+            }
+        }
+
+        flags
+    }
+
+    // The environment is only read the first time, later checks are a load of the cached flags
+    pub fn get() -> LogFlags {
+        *FLAGS.get_or_init(LogFlags::from_env)
+    }
+}
@@ -1,41 +1,284 @@
-use std::io::{stdin, stdout, Write};
+use std::io::{stdin, stdout, IsTerminal, Write};
+use std::path::Path;
+use std::time::Instant;
 
+mod arithmetic;
 mod input_lexer;
 mod input_parser;
 mod ast;
 mod engine;
+mod expand;
+mod line_editor;
+mod log;
 
-use input_lexer::*;
-use input_parser::*;
 use engine::*;
+use input_lexer::InputLexer;
+use log::LogFlags;
+
+// Terminals wrap pasted text in these escape sequences when bracketed paste is enabled
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
+// Startup file in the home directory, run before the first prompt
+const RCFILE: &str = ".phoenixrc";
+
+// Startup file in the home directory which only a login shell runs, once and before the rc file
+const PROFILE: &str = ".phoenix_profile";
 
 fn main() {
+    let started = Instant::now();
+    let mut args = std::env::args();
+
+    // Like other shells, being started with a '-' in front of the name such as '-phoenix' means it's a login shell
+    let mut login = args.next().is_some_and(|name| name.starts_with('-'));
+    let mut banner = true;
+    let mut script = None;
+    let mut command = None;
+    let mut stay = false;
+    let mut rcfile = None;
+    let mut norc = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--version" | "-V" => {
+                print_version();
+                return;
+            }
+
+            "--no-banner" => banner = false,
+
+            // Runs another file at startup instead of '~/.phoenixrc', such as to try out a different configuration
+            "--rcfile" => match args.next() {
+                Some(path) => rcfile = Some(path),
+                None => {
+                    eprintln!("Phoenix: --rcfile requires a path");
+                    std::process::exit(2);
+                }
+            },
+
+            // Starts without running any rc file, for debugging or runs which must not depend on the user's setup
+            "--norc" => norc = true,
+
+            // Runs '~/.phoenix_profile' at startup as well, for use as the shell started when logging in
+            "--login" | "-l" => login = true,
+
+            // Keeps the prompt open after '-c' or a script, with whatever state they left behind
+            "-i" => stay = true,
+
+            // The words after the command string become '$0' and the positional parameters
+            "-c" => match args.next() {
+                Some(text) => {
+                    command = Some(text);
+                    break;
+                }
+                None => {
+                    eprintln!("Phoenix: -c requires a command");
+                    std::process::exit(2);
+                }
+            },
+
+            option if option.starts_with('-') => {
+                eprintln!("Phoenix: unknown option '{}'", option);
+                std::process::exit(2);
+            }
+
+            // The first operand is a script to run, everything after it is passed to the script
+            _ => {
+                script = Some(arg);
+                break;
+            }
+        }
+    }
+
+    // Tracing is chosen once, before anything is lexed
+    LogFlags::get();
+
+    if norc && rcfile.is_some() {
+        eprintln!("Phoenix: --norc and --rcfile can't be used together, --norc skips the file --rcfile names");
+        std::process::exit(2);
+    }
+
+    // Unlike the default file, one asked for by name is expected to be there
+    if let Some(path) = rcfile.as_ref().filter(|path| !Path::new(path).exists()) {
+        eprintln!("Phoenix: rc file '{}' does not exist", path);
+        std::process::exit(2);
+    }
+
     let mut engine = Engine::new();
+
+    // The profile applies to a login shell however it's used, even to run a single command
+    if login {
+        run_home_file(&mut engine, PROFILE);
+    }
+
+    // Only a shell which will show a prompt runs the rc file, '-c' and scripts start without it like other shells
+    if !norc && (stay || (command.is_none() && script.is_none())) {
+        run_rcfile(&mut engine, rcfile);
+    }
+
+    if let Some(command) = command {
+        if let Err(err) = engine.run_command(&command, args.collect()) {
+            eprintln!("Phoenix: {}", err);
+            engine.last_status = 1;
+        }
+
+        if !stay {
+            std::process::exit(engine.last_status);
+        }
+    } else if let Some(path) = script {
+        if let Err(err) = engine.run_script(&path, args.collect()) {
+            eprintln!("Phoenix: {}: {}", path, err);
+            std::process::exit(127);
+        }
+
+        if !stay {
+            std::process::exit(engine.last_status);
+        }
+    }
+
     let mut stdin_buffer;
-    let mut stdout = stdout();
-    let stdin = stdin();
-    
-    // TODO: Empty command freezes / causes infinite loop
+
+    // Piped input is read one line at a time, and commands reading stdin such as 'read' or 'cat' get what follows
+    // the current line. A terminal instead has pasted lines gathered up and run together.
+    let mut interactive = stdin().is_terminal();
+
+    // Only greet a person at a terminal, not input piped in from another program
+    if banner && interactive {
+        println!("Phoenix {}", env!("CARGO_PKG_VERSION"));
+    }
+
+    if std::env::var_os("PHOENIX_DEBUG").is_some() {
+        eprintln!("Phoenix: started in {:?}", started.elapsed());
+    }
+
     loop {
+        engine.notify_jobs();
+
+        // 'exec' may have pointed stdin somewhere else since the last prompt
+        interactive = stdin().is_terminal();
+
         stdin_buffer = String::new();
-        print!("{}>", engine.cur_dir);
-        stdout.flush().expect("Unable to flush stdout!");
+        let read = read_input(&engine, interactive, &engine.prompt(), &mut stdin_buffer);
+
+        // Ctrl+D on an empty line, or the end of piped input, leaves the shell as 'exit' would
+        if read == 0 {
+            if interactive {
+                println!("exit");
+            }
+
+            std::process::exit(engine.last_status);
+        }
+
+        // Lines pasted together arrive at once, so run them as one input rather than one per prompt
+        while interactive && stdin_buffer.ends_with('\n') && input_pending() {
+            let read = read_input(&engine, interactive, "", &mut stdin_buffer);
 
-        stdin.read_line(&mut stdin_buffer)
-            .expect("Unable to read line from stdin!");
+            if read == 0 {
+                break;
+            }
+        }
 
-        let stdin_bytes = stdin_buffer.as_bytes().into();
+        // A bracketed paste may end on a line of its own, so read up to the closing marker
+        if stdin_buffer.contains(PASTE_START) {
+            while !stdin_buffer.contains(PASTE_END) {
+                let read = read_input(&engine, interactive, "", &mut stdin_buffer);
 
-        let lexer = InputLexer::new(stdin_bytes);
+                if read == 0 {
+                    break;
+                }
+            }
+        }
 
-        let tokens = lexer
-            .filter(|token| token.typ != TokenType::Whitespace)
-            .collect();
+        // Keep reading while the input continues on following lines, such as the body of a here-document
+        while InputLexer::is_incomplete(&stdin_buffer) {
+            let read = read_input(&engine, interactive, "> ", &mut stdin_buffer);
 
-        let mut parser = InputParser::new(&*stdin_buffer, tokens);
+            if read == 0 {
+                break;
+            }
+        }
 
-        let module = parser.build_ast();
+        // The markers aren't part of the command, the pasted block is run like any other input
+        stdin_buffer = stdin_buffer.replace(PASTE_START, "").replace(PASTE_END, "");
+
+        // A line starting with '!' re-runs an earlier command, and is recorded as that command
+        let Some(line) = engine.expand_history(&stdin_buffer) else {
+            continue;
+        };
+
+        engine.add_history(&line);
+        // The prompt carries on after an I/O error, such as a failure to write to the terminal
+        if let Err(err) = engine.run("stdin", &line) {
+            eprintln!("Phoenix: {}", err);
+            engine.last_status = 1;
+        }
+    }
+}
+
+// Prints the version along with the target and commit to include in bug reports
+fn print_version() {
+    let version = env!("CARGO_PKG_VERSION");
+
+    match option_env!("PHOENIX_GIT_COMMIT") {
+        Some(commit) => println!("Phoenix {} ({}, {})", version, std::env::consts::OS, commit),
+        None => println!("Phoenix {} ({})", version, std::env::consts::OS)
+    }
+}
 
-        engine.execute(stdin_buffer.as_str(), module);
+// Runs the rc file in the shell before the first prompt, a missing '~/.phoenixrc' just means there's nothing to set up
+fn run_rcfile(engine: &mut Engine, rcfile: Option<String>) {
+    match rcfile {
+        Some(path) => run_startup_file(engine, &path),
+        None => run_home_file(engine, RCFILE)
     }
 }
+
+// Runs a startup file from the home directory if there is one
+fn run_home_file(engine: &mut Engine, name: &str) {
+    let path = Path::new(&Engine::get_base_dir()).join(name);
+
+    if path.exists() {
+        run_startup_file(engine, &path.to_string_lossy());
+    }
+}
+
+// A broken startup file shouldn't keep the shell from starting
+fn run_startup_file(engine: &mut Engine, path: &str) {
+    if let Err(err) = engine.run_file(path) {
+        eprintln!("Phoenix: {}: {}", path, err);
+    }
+}
+
+// Lines typed at a terminal go through the line editor for editing and completion, piped input is read as it comes
+fn read_input(engine: &Engine, interactive: bool, prompt: &str, buffer: &mut String) -> usize {
+    let read = if interactive {
+        line_editor::read_line(
+            prompt,
+            buffer,
+            engine.history(),
+            |line, cursor| engine.complete(line, cursor),
+            |word| engine.abbreviation(word)
+        )
+    } else {
+        print!("{}", prompt);
+        stdout().flush().expect("Unable to flush stdout!");
+        read_stdin_line(buffer)
+    };
+
+    read.expect("Unable to read line from stdin!")
+}
+
+// Whether more input can be read from stdin without blocking
+#[cfg(target_os = "linux")]
+fn input_pending() -> bool {
+    let mut fds = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+
+    // Safety: 'fds' is a single valid pollfd and a zero timeout returns immediately
+    unsafe { libc::poll(&mut fds, 1, 0) > 0 && fds.revents & libc::POLLIN != 0 }
+}
+
+#[cfg(target_os = "windows")]
+fn input_pending() -> bool {
+    // The console delivers pasted lines one read at a time, which are run as they arrive
+    false
+}